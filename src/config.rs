@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::LevelFilter;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::loader::LoaderConfig;
+use crate::music::MusicConfig;
+use crate::tuning::TuningConfig;
+use crate::validate::ValidationConfig;
+
+pub const CONFIG_FILE: &'static str = "config.toml";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub log: LogConfig,
+    pub loader: LoaderConfig,
+    pub tuning: TuningConfig,
+    pub music: MusicConfig,
+    pub validation: ValidationConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    // Relative paths end up next to the game executable.
+    pub file: Option<String>,
+    pub stdout: bool,
+    // Per-module overrides, e.g. `dlc_data = "trace"`.
+    pub targets: BTreeMap<String, LevelFilter>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: LevelFilter::Info,
+            file: Some("sa2_dlc_mod.log".to_string()),
+            stdout: false,
+            targets: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P>(path: P) -> Result<Config>
+    where
+        P: AsRef<Path>,
+    {
+        load_toml_or_default(path)
+    }
+}
+
+// For the optional TOML files: a missing file just means the defaults.
+pub fn load_toml_or_default<T, P>(path: P) -> Result<T>
+where
+    T: DeserializeOwned + Default,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e.into()),
+    };
+    toml::from_str(&text)
+        .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+}
@@ -1,15 +1,229 @@
-use std::io::{self, Read, Seek, SeekFrom, Cursor};
+use std::io::{self, Read, Write, Seek, SeekFrom, Cursor};
 use std::iter;
 
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LE};
+use log::{debug, log_enabled, trace, warn, Level};
 use prs_util::decoder::Decoder;
 
-use crate::model::{DlcText, KartStats, KartDlc};
+use crate::error::{Error, Result};
+use crate::level::LevelId;
+use crate::model::{DlcDescriptor, DlcText, KartStats, KartDlc};
+use crate::prs::{self, Compression};
+use crate::set_file::SetFile;
+use crate::vms::{self, VmsHeader};
 
 const SAVE_BASE: u32 = 0x8cb00000;
+// Covers the four fixed entries swapped by swap_texture_header.
+const TEXTURE_HEADER_LEN: usize = 0x2e + 0x26 * 3 + 4;
+const MAX_NJS_DEPTH: usize = 1024;
 
 trait DlcRead: Sized {
-    fn read_from<R>(read: R) -> io::Result<Self> where R: Read + Seek;
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self> where R: Read + Seek;
+}
+
+// Wraps the data being parsed so pointers and lengths can be checked against
+// its size, and keeps track of which field is being read for error messages.
+struct DlcReader<R> {
+    inner: R,
+    len: u64,
+    path: Vec<String>,
+}
+
+impl<R> DlcReader<R>
+where
+    R: Read + Seek,
+{
+    fn new(mut inner: R, root: &str) -> Result<DlcReader<R>> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(0))?;
+        Ok(DlcReader {
+            inner: inner,
+            len: len,
+            path: vec![root.to_string()],
+        })
+    }
+
+    // For data found inside the current field, such as a blob within a blob.
+    fn nested<N>(&self, inner: N) -> Result<DlcReader<N>>
+    where
+        N: Read + Seek,
+    {
+        let mut read = DlcReader::new(inner, "")?;
+        read.path = self.path.clone();
+        Ok(read)
+    }
+
+    fn field<T, S>(&mut self, name: S) -> Result<T>
+    where
+        T: DlcRead,
+        S: Into<String>,
+    {
+        self.path.push(name.into());
+        let res = T::read_from(self).map_err(|e| self.annotate(e));
+        self.path.pop();
+        res
+    }
+
+    fn path(&self) -> String {
+        self.path.join(".")
+    }
+
+    fn error<S>(&self, reason: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error::Malformed {
+            path: self.path(),
+            reason: reason.into(),
+        }
+    }
+
+    // Plain io errors (running off the end, mostly) get the path added.
+    // Anything else already names the innermost field, which is the one
+    // worth reporting.
+    fn annotate(&self, err: Error) -> Error {
+        match err {
+            Error::Io(e) => self.error(e.to_string()),
+            err => err,
+        }
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(Error::OutOfRange {
+                path: self.path(),
+                offset: offset,
+                len: len,
+                size: self.len,
+            }),
+        }
+    }
+
+    fn bad_pointer(&self, pointer: u32) -> Error {
+        Error::BadPointer {
+            path: self.path(),
+            pointer: pointer,
+        }
+    }
+}
+
+impl<R> Read for DlcReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R> Seek for DlcReader<R>
+where
+    R: Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+trait DlcWrite {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()>;
+}
+
+struct Reloc {
+    pos: usize,
+    base: u32,
+    with_len: bool,
+    target: DlcWriter,
+}
+
+// Collects the bytes of a structure along with the blocks its pointers and
+// (offset, len) pairs refer to. Nothing has an address until layout, which
+// places each block after the block pointing to it, depth first in field
+// order, and then fills in the pointers.
+struct DlcWriter {
+    data: Vec<u8>,
+    relocs: Vec<Reloc>,
+}
+
+impl DlcWriter {
+    fn new() -> DlcWriter {
+        DlcWriter {
+            data: Vec::new(),
+            relocs: Vec::new(),
+        }
+    }
+
+    fn from_value<T>(value: &T) -> Result<DlcWriter>
+    where
+        T: DlcWrite + ?Sized,
+    {
+        let mut write = DlcWriter::new();
+        value.write_to(&mut write)?;
+        Ok(write)
+    }
+
+    fn reloc(&mut self, base: u32, with_len: bool, target: DlcWriter) -> Result<()> {
+        self.relocs.push(Reloc {
+            pos: self.data.len(),
+            base: base,
+            with_len: with_len,
+            target: target,
+        });
+        self.write_u32::<LE>(0)?;
+        if with_len {
+            self.write_u32::<LE>(0)?;
+        }
+        Ok(())
+    }
+
+    fn layout(self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.layout_into(&mut out)?;
+        Ok(out)
+    }
+
+    fn layout_into(self, out: &mut Vec<u8>) -> Result<u32> {
+        align(out, 4);
+        let start = out.len();
+        out.extend_from_slice(&self.data);
+        for reloc in self.relocs {
+            let len = reloc.target.data.len() as u32;
+            let target_start = reloc.target.layout_into(out)?;
+            let mut slot = &mut out[start + reloc.pos..];
+            slot.write_u32::<LE>(reloc.base + target_start)?;
+            if reloc.with_len {
+                slot.write_u32::<LE>(len)?;
+            }
+        }
+        Ok(start as u32)
+    }
+}
+
+impl Write for DlcWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, T> DlcWrite for &'a T
+where
+    T: DlcWrite + ?Sized,
+{
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        (**self).write_to(write)
+    }
+}
+
+impl DlcWrite for [u8] {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        write.write_all(self)?;
+        Ok(())
+    }
 }
 
 struct Pointer<T>(T);
@@ -18,49 +232,73 @@ impl<T> DlcRead for Pointer<T>
 where
     T: DlcRead,
 {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
         let addr = read.read_u32::<LE>()?;
+        if addr as u64 >= read.len {
+            return Err(read.bad_pointer(addr));
+        }
         let save_addr = read.seek(SeekFrom::Current(0))?;
         read.seek(SeekFrom::Start(addr as u64))?;
-        let inner = T::read_from(&mut read)?;
+        let inner = T::read_from(read)?;
         read.seek(SeekFrom::Start(save_addr))?;
         Ok(Pointer(inner))
     }
 }
 
+impl<T> DlcWrite for Pointer<T>
+where
+    T: DlcWrite,
+{
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        write.reloc(0, false, DlcWriter::from_value(&self.0)?)
+    }
+}
+
 struct VmuPointer<T>(T);
 
 impl<T> DlcRead for VmuPointer<T>
 where
     T: DlcRead,
 {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
-        let addr = read.read_u32::<LE>()? - SAVE_BASE;
+        let vmu_addr = read.read_u32::<LE>()?;
+        let addr = vmu_addr.checked_sub(SAVE_BASE)
+            .filter(|&addr| (addr as u64) < read.len)
+            .ok_or_else(|| read.bad_pointer(vmu_addr))?;
         let save_addr = read.seek(SeekFrom::Current(0))?;
         read.seek(SeekFrom::Start(addr as u64))?;
-        let inner = T::read_from(&mut read)?;
+        let inner = T::read_from(read)?;
         read.seek(SeekFrom::Start(save_addr))?;
         Ok(VmuPointer(inner))
     }
 }
 
-struct OffsetLen(Vec<u8>);
+impl<T> DlcWrite for VmuPointer<T>
+where
+    T: DlcWrite,
+{
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        write.reloc(SAVE_BASE, false, DlcWriter::from_value(&self.0)?)
+    }
+}
+
+struct OffsetLen<B = Vec<u8>>(B);
 
 impl DlcRead for OffsetLen {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
         let addr = read.read_u32::<LE>()?;
-        println!("Addr: 0x{:08x}", addr);
         let len = read.read_u32::<LE>()?;
-        println!("Len: 0x{:08x}", len);
+        trace!("{}: offset 0x{:08x} len 0x{:08x}", read.path(), addr, len);
+        read.check_range(addr as u64, len as u64)?;
         let save_addr = read.seek(SeekFrom::Current(0))?;
         read.seek(SeekFrom::Start(addr as u64))?;
         let mut inner: Vec<u8> = iter::repeat(0).take(len as usize).collect();
@@ -70,8 +308,17 @@ impl DlcRead for OffsetLen {
     }
 }
 
+impl<B> DlcWrite for OffsetLen<B>
+where
+    B: AsRef<[u8]>,
+{
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        write.reloc(0, true, DlcWriter::from_value(self.0.as_ref())?)
+    }
+}
+
 impl DlcRead for [u8; 128] {
-    fn read_from<R>(read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
@@ -88,16 +335,26 @@ impl DlcRead for [u8; 128] {
     }
 }
 
+// Text is read up to the first NUL, so only that much gets written back.
+impl DlcWrite for [u8; 128] {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        let end = self.iter().position(|&b| b == 0).unwrap_or_else(|| self.len());
+        write.write_all(&self[..end])?;
+        write.write_u8(0)?;
+        Ok(())
+    }
+}
+
 impl DlcRead for DlcText {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
-        let title = VmuPointer::<[u8;128]>::read_from(&mut read)?;
-        let dlc_type = VmuPointer::<[u8;128]>::read_from(&mut read)?;
-        let stage = VmuPointer::<[u8;128]>::read_from(&mut read)?;
-        let character = VmuPointer::<[u8;128]>::read_from(&mut read)?;
-        let description = VmuPointer::<[u8;128]>::read_from(&mut read)?;
+        let title = read.field::<VmuPointer<[u8;128]>, _>("title")?;
+        let dlc_type = read.field::<VmuPointer<[u8;128]>, _>("dlc_type")?;
+        let stage = read.field::<VmuPointer<[u8;128]>, _>("stage")?;
+        let character = read.field::<VmuPointer<[u8;128]>, _>("character")?;
+        let description = read.field::<VmuPointer<[u8;128]>, _>("description")?;
 
         Ok(DlcText {
             title: title.0,
@@ -109,8 +366,18 @@ impl DlcRead for DlcText {
     }
 }
 
+impl DlcWrite for DlcText {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        VmuPointer(&self.title).write_to(write)?;
+        VmuPointer(&self.dlc_type).write_to(write)?;
+        VmuPointer(&self.stage).write_to(write)?;
+        VmuPointer(&self.character).write_to(write)?;
+        VmuPointer(&self.description).write_to(write)
+    }
+}
+
 impl DlcRead for KartStats {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
@@ -140,12 +407,28 @@ impl DlcRead for KartStats {
     }
 }
 
+impl DlcWrite for KartStats {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        write.write_f32::<LE>(self.accel)?;
+        write.write_f32::<LE>(self.brake_force)?;
+        write.write_f32::<LE>(self.no_accel_force)?;
+        write.write_f32::<LE>(self.max_drive_speed)?;
+        write.write_f32::<LE>(self.gravity)?;
+        write.write_f32::<LE>(self.unknown1)?;
+        write.write_f32::<LE>(self.drift_factor)?;
+        write.write_f32::<LE>(self.drift_threshold)?;
+        write.write_f32::<LE>(self.unknown2)?;
+        write.write_f32::<LE>(self.hard_speed_cap)?;
+        Ok(())
+    }
+}
+
 impl DlcRead for KartDlc {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
-        let stats = KartStats::read_from(&mut read)?;
+        let stats = KartStats::read_from(read)?;
         let autorun_slot_handicap_1 = read.read_f32::<LE>()?;
         let autorun_rank_handicap_1 = read.read_f32::<LE>()?;
         let autorun_not_first_handicap_1 = read.read_f32::<LE>()?;
@@ -170,92 +453,232 @@ impl DlcRead for KartDlc {
     }
 }
 
-fn rebase_njs_model(data: &mut [u8], model_offset: usize) -> io::Result<()> {
+impl DlcWrite for KartDlc {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        self.stats.write_to(write)?;
+        write.write_f32::<LE>(self.autorun_slot_handicap_1)?;
+        write.write_f32::<LE>(self.autorun_rank_handicap_1)?;
+        write.write_f32::<LE>(self.autorun_not_first_handicap_1)?;
+        write.write_f32::<LE>(self.autorun_slot_handicap_2)?;
+        write.write_f32::<LE>(self.autorun_rank_handicap_2)?;
+        write.write_f32::<LE>(self.autorun_not_first_handicap_2)?;
+        write.write_u32::<LE>(self.ai_use_dlc_kart)?;
+        write.write_all(&self.song_name)?;
+        Ok(())
+    }
+}
+
+fn align(data: &mut Vec<u8>, alignment: usize) {
+    while data.len() % alignment != 0 {
+        data.push(0);
+    }
+}
+
+fn read_field(data: &[u8], pos: usize, path: &str) -> Result<u32> {
+    data.get(pos..)
+        .and_then(|mut field| field.read_u32::<LE>().ok())
+        .ok_or_else(|| rebase_error(path, format!("field at 0x{:x} is past the end of the 0x{:x} byte blob", pos, data.len())))
+}
+
+// Reads an offset into data, checking it points inside. Zero means none.
+fn read_offset(data: &[u8], pos: usize, path: &str) -> Result<u32> {
+    let offset = read_field(data, pos, path)?;
+    if offset as usize >= data.len() {
+        return Err(rebase_error(path, format!("offset 0x{:x} is outside the 0x{:x} byte blob", offset, data.len())));
+    }
+    Ok(offset)
+}
+
+// Where offset will be in memory, since data is handed to the game in place.
+fn pointer_to(data: &[u8], offset: u32, path: &str) -> Result<u32> {
     let data_base = data.as_ptr() as u32;
+    data_base.checked_add(offset)
+        .ok_or_else(|| rebase_error(path, format!("offset 0x{:x} from 0x{:08x} overflows", offset, data_base)))
+}
+
+fn write_pointer(data: &mut [u8], pos: usize, offset: u32, path: &str) -> Result<()> {
+    let pointer = pointer_to(data, offset, path)?;
+    (&mut data[pos..]).write_u32::<LE>(pointer)?;
+    Ok(())
+}
+
+// Paths name the blob, then the offset of the struct within it and its field.
+fn rebase_njs_model(data: &mut [u8], model_offset: usize, blob: &str) -> Result<()> {
+    for &(field, name) in [(0x00, "vertices"), (0x04, "normals")].iter() {
+        let path = format!("{}@0x{:x}.{}", blob, model_offset, name);
+        let offset = read_offset(data, model_offset + field, &path)?;
+        if offset != 0 {
+            write_pointer(data, model_offset + field, offset, &path)?;
+        }
+    }
+    Ok(())
+}
+
+fn rebase_njs_obj(data: &mut [u8], obj_offset: usize, depth: usize, blob: &str) -> Result<()> {
+    // Children and siblings are followed before being rewritten, so a cycle
+    // would recurse forever.
+    if depth > MAX_NJS_DEPTH {
+        return Err(rebase_error(&format!("{}@0x{:x}", blob, obj_offset), format!("object tree deeper than {}", MAX_NJS_DEPTH)));
+    }
+
+    let path = format!("{}@0x{:x}.model", blob, obj_offset);
+    let model_offset = read_offset(data, obj_offset + 0x04, &path)?;
+    if model_offset != 0 {
+        rebase_njs_model(data, model_offset as usize, blob)?;
+        write_pointer(data, obj_offset + 0x04, model_offset, &path)?;
+    }
+
+    let path = format!("{}@0x{:x}.child", blob, obj_offset);
+    let child_offset = read_offset(data, obj_offset + 0x2c, &path)?;
+    if child_offset != 0 {
+        rebase_njs_obj(data, child_offset as usize, depth + 1, blob)?;
+        write_pointer(data, obj_offset + 0x2c, child_offset, &path)?;
+    }
+
+    let path = format!("{}@0x{:x}.sibling", blob, obj_offset);
+    let sibling_offset = read_offset(data, obj_offset + 0x30, &path)?;
+    if sibling_offset != 0 {
+        rebase_njs_obj(data, sibling_offset as usize, depth + 1, blob)?;
+        write_pointer(data, obj_offset + 0x30, sibling_offset, &path)?;
+    }
+
+    Ok(())
+}
+
+fn rebase_njs_texname(data: &mut [u8], name_offset: usize, blob: &str) -> Result<()> {
+    trace!("texname {:08x}", name_offset);
+    let path = format!("{}@0x{:x}.filename", blob, name_offset);
+    let filename_offset = read_offset(data, name_offset, &path)?;
+    if filename_offset != 0 {
+        write_pointer(data, name_offset, filename_offset, &path)?;
+    }
+
+    Ok(())
+}
+
+fn rebase_njs_texlist(data: &mut [u8], tex_offset: usize, blob: &str) -> Result<()> {
+    trace!("texlist {:08x}", tex_offset);
+    let path = format!("{}@0x{:x}.names", blob, tex_offset);
+    let name_offset = read_offset(data, tex_offset, &path)?;
+    let num_names = read_field(data, tex_offset + 4, &format!("{}@0x{:x}.num_names", blob, tex_offset))?;
+    if name_offset != 0 && num_names != 0 {
+        for idx in 0..num_names {
+            let entry_offset = idx.checked_mul(0xc)
+                .and_then(|rel| rel.checked_add(name_offset))
+                .ok_or_else(|| rebase_error(&path, format!("name {} is past the end of the 0x{:x} byte blob", idx, data.len())))?;
+            rebase_njs_texname(data, entry_offset as usize, blob)?;
+        }
+        write_pointer(data, tex_offset, name_offset, &path)?;
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::identity_op)]
+fn unrebase_njs_model(data: &mut [u8], model_offset: usize, data_base: u32) -> io::Result<()> {
     let mut cursed = Cursor::new(data);
 
     cursed.seek(SeekFrom::Start(model_offset as u64 + 0x00))?;
-    let vert_offset = cursed.read_u32::<LE>()?;
-    if vert_offset != 0 {
+    let vert_ptr = cursed.read_u32::<LE>()?;
+    if vert_ptr != 0 {
         cursed.seek(SeekFrom::Start(model_offset as u64 + 0x00))?;
-        cursed.write_u32::<LE>(vert_offset + data_base)?;
+        cursed.write_u32::<LE>(vert_ptr - data_base)?;
     }
 
     cursed.seek(SeekFrom::Start(model_offset as u64 + 0x04))?;
-    let norm_offset = cursed.read_u32::<LE>()?;
-    if norm_offset != 0 {
+    let norm_ptr = cursed.read_u32::<LE>()?;
+    if norm_ptr != 0 {
         cursed.seek(SeekFrom::Start(model_offset as u64 + 0x04))?;
-        cursed.write_u32::<LE>(norm_offset + data_base)?;
+        cursed.write_u32::<LE>(norm_ptr - data_base)?;
     }
 
     Ok(())
 }
 
-fn rebase_njs_obj(data: &mut [u8], obj_offset: usize) -> io::Result<()> {
-    let data_base = data.as_ptr() as u32;
+// Inverse of rebase_njs_obj. data_base is where the rebased copy lived.
+fn unrebase_njs_obj(data: &mut [u8], obj_offset: usize, data_base: u32) -> io::Result<()> {
     let mut cursed = Cursor::new(data);
 
     cursed.seek(SeekFrom::Start(obj_offset as u64 + 0x04))?;
-    let model_offset = cursed.read_u32::<LE>()?;
-    if model_offset != 0 {
-        rebase_njs_model(cursed.get_mut(), model_offset as usize)?;
+    let model_ptr = cursed.read_u32::<LE>()?;
+    if model_ptr != 0 {
+        let model_offset = model_ptr - data_base;
+        unrebase_njs_model(cursed.get_mut(), model_offset as usize, data_base)?;
         cursed.seek(SeekFrom::Start(obj_offset as u64 + 0x04))?;
-        cursed.write_u32::<LE>(model_offset + data_base)?;
+        cursed.write_u32::<LE>(model_offset)?;
     }
 
     cursed.seek(SeekFrom::Start(obj_offset as u64 + 0x2c))?;
-    let child_offset = cursed.read_u32::<LE>()?;
-    if child_offset != 0 {
-        rebase_njs_obj(cursed.get_mut(), child_offset as usize)?;
+    let child_ptr = cursed.read_u32::<LE>()?;
+    if child_ptr != 0 {
+        let child_offset = child_ptr - data_base;
+        unrebase_njs_obj(cursed.get_mut(), child_offset as usize, data_base)?;
         cursed.seek(SeekFrom::Start(obj_offset as u64 + 0x2c))?;
-        cursed.write_u32::<LE>(child_offset + data_base)?;
+        cursed.write_u32::<LE>(child_offset)?;
     }
 
     cursed.seek(SeekFrom::Start(obj_offset as u64 + 0x30))?;
-    let sibling_offset = cursed.read_u32::<LE>()?;
-    if sibling_offset != 0 {
-        rebase_njs_obj(cursed.get_mut(), sibling_offset as usize)?;
+    let sibling_ptr = cursed.read_u32::<LE>()?;
+    if sibling_ptr != 0 {
+        let sibling_offset = sibling_ptr - data_base;
+        unrebase_njs_obj(cursed.get_mut(), sibling_offset as usize, data_base)?;
         cursed.seek(SeekFrom::Start(obj_offset as u64 + 0x30))?;
-        cursed.write_u32::<LE>(sibling_offset + data_base)?;
+        cursed.write_u32::<LE>(sibling_offset)?;
     }
 
     Ok(())
 }
 
-fn rebase_njs_texname(data: &mut [u8], name_offset: usize) -> io::Result<()> {
-    let data_base = data.as_ptr() as u32;
-    let mut cursed = Cursor::new(data);
-    println!("texname {:08x}", name_offset);
-
-    cursed.seek(SeekFrom::Start(name_offset as u64))?;
-    let filename_offset = cursed.read_u32::<LE>()?;
-    if filename_offset != 0 {
-        cursed.seek(SeekFrom::Start(name_offset as u64))?;
-        cursed.write_u32::<LE>(filename_offset + data_base)?;
-    }
-
-    Ok(())
-}
-
-fn rebase_njs_texlist(data: &mut [u8], tex_offset: usize) -> io::Result<()> {
-    println!("texlist {:08x}", tex_offset);
-    let data_base = data.as_ptr() as u32;
+fn unrebase_njs_texlist(data: &mut [u8], tex_offset: usize, data_base: u32) -> io::Result<()> {
     let mut cursed = Cursor::new(data);
 
     cursed.seek(SeekFrom::Start(tex_offset as u64))?;
-    let name_offset = cursed.read_u32::<LE>()?;
+    let name_ptr = cursed.read_u32::<LE>()?;
     let num_names = cursed.read_u32::<LE>()?;
-    if name_offset != 0 && num_names != 0 {
+    if name_ptr != 0 && num_names != 0 {
+        let name_offset = name_ptr - data_base;
         for idx in 0..num_names {
-            rebase_njs_texname(cursed.get_mut(), (name_offset + 0xc * idx) as usize)?;
+            let entry_offset = (name_offset + 0xc * idx) as u64;
+            cursed.seek(SeekFrom::Start(entry_offset))?;
+            let filename_ptr = cursed.read_u32::<LE>()?;
+            if filename_ptr != 0 {
+                cursed.seek(SeekFrom::Start(entry_offset))?;
+                cursed.write_u32::<LE>(filename_ptr - data_base)?;
+            }
         }
         cursed.seek(SeekFrom::Start(tex_offset as u64))?;
-        cursed.write_u32::<LE>(name_offset + data_base)?;
+        cursed.write_u32::<LE>(name_offset)?;
     }
 
     Ok(())
 }
 
+fn rebase_error<S>(path: &str, reason: S) -> Error
+where
+    S: Into<String>,
+{
+    Error::ModelRebase {
+        path: path.to_string(),
+        reason: reason.into(),
+    }
+}
+
+// The texture header is byte swapped in place on load. Swapping is its own
+// inverse, so this also restores the on-disk layout.
+fn swap_texture_header(texture: &mut [u8]) {
+    // swap endianness of thing
+    texture.swap(8, 9);
+    // swap endianness of num textures
+    texture.swap(10, 11);
+
+    let mut offset = 0x2e;
+    for _ in 0..4 {
+        texture.swap(offset, offset + 3);
+        texture.swap(offset + 1, offset + 2);
+        offset += 0x26;
+    }
+}
+
 pub struct DlcModelData {
     model: Vec<u8>,
     texlist: Vec<u8>,
@@ -265,35 +688,31 @@ pub struct DlcModelData {
 }
 
 impl DlcRead for DlcModelData {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
-        let mut model = OffsetLen::read_from(&mut read)?.0;
-        let mut texlist = OffsetLen::read_from(&mut read)?.0;
-        let mut texture = OffsetLen::read_from(&mut read)?.0;
+        let mut model = read.field::<OffsetLen, _>("model")?.0;
+        let mut texlist = read.field::<OffsetLen, _>("texlist")?.0;
+        let mut texture = read.field::<OffsetLen, _>("texture")?.0;
 
-        let mut model_slice: &[u8] = &model;
-        let obj_offset = model_slice.read_u32::<LE>()?;
-        rebase_njs_obj(&mut model, obj_offset as usize)?;
-        let obj_raw_ptr = model.as_ptr() as u32 + obj_offset;
+        let model_path = format!("{}.model", read.path());
+        let root_path = format!("{}@0x0.root", model_path);
+        let obj_offset = read_offset(&model, 0, &root_path)?;
+        rebase_njs_obj(&mut model, obj_offset as usize, 0, &model_path)?;
+        let obj_raw_ptr = pointer_to(&model, obj_offset, &root_path)?;
 
-        let mut texlist_slice: &[u8] = &texlist;
-        let texlist_offset = texlist_slice.read_u32::<LE>()?;
-        rebase_njs_texlist(&mut texlist, texlist_offset as usize)?;
-        let texlist_raw_ptr = texlist.as_ptr() as u32 + texlist_offset;
+        let texlist_path = format!("{}.texlist", read.path());
+        let root_path = format!("{}@0x0.root", texlist_path);
+        let texlist_offset = read_offset(&texlist, 0, &root_path)?;
+        rebase_njs_texlist(&mut texlist, texlist_offset as usize, &texlist_path)?;
+        let texlist_raw_ptr = pointer_to(&texlist, texlist_offset, &root_path)?;
 
-        // swap endianness of thing
-        texture.swap(8, 9);
-        // swap endianness of num textures
-        texture.swap(10, 11);
-
-        let mut offset = 0x2e;
-        for _ in 0..4 {
-            texture.swap(offset, offset + 3);
-            texture.swap(offset + 1, offset + 2);
-            offset += 0x26;
+        if texture.len() < TEXTURE_HEADER_LEN {
+            return Err(read.error(format!("texture is 0x{:x} bytes, too short for its 0x{:x} byte header",
+                texture.len(), TEXTURE_HEADER_LEN)));
         }
+        swap_texture_header(&mut texture);
 
         Ok(DlcModelData {
             model: model,
@@ -305,160 +724,948 @@ impl DlcRead for DlcModelData {
     }
 }
 
+impl DlcWrite for DlcModelData {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        OffsetLen(self.raw_model()?).write_to(write)?;
+        OffsetLen(self.raw_texlist()?).write_to(write)?;
+        OffsetLen(self.raw_texture()).write_to(write)
+    }
+}
+
+impl DlcModelData {
+    // Builds model data from the on-disk blobs, as if read from a DLC.
+    pub fn from_raw(model: &[u8], texlist: &[u8], texture: &[u8]) -> Result<DlcModelData> {
+        let mut write = DlcWriter::new();
+        OffsetLen(model).write_to(&mut write)?;
+        OffsetLen(texlist).write_to(&mut write)?;
+        OffsetLen(texture).write_to(&mut write)?;
+        let data = write.layout()?;
+        DlcModelData::read_from(&mut DlcReader::new(Cursor::new(data), "model_data")?)
+    }
+
+    // Texture file names listed in the texlist, in order.
+    pub fn texture_names(&self) -> Result<Vec<String>> {
+        let texlist = self.raw_texlist()?;
+        let mut read = DlcReader::new(Cursor::new(&texlist[..]), "texlist")?;
+        let texlist_offset = read.read_u32::<LE>()?;
+        read.seek(SeekFrom::Start(texlist_offset as u64))?;
+        let name_offset = read.read_u32::<LE>()?;
+        let num_names = read.read_u32::<LE>()?;
+        let mut names = Vec::new();
+        for idx in 0..num_names {
+            read.seek(SeekFrom::Start((name_offset + 0xc * idx) as u64))?;
+            let filename_offset = read.read_u32::<LE>()?;
+            read.check_range(filename_offset as u64, 0)?;
+            read.seek(SeekFrom::Start(filename_offset as u64))?;
+            let name = <[u8; 128]>::read_from(&mut read)?;
+            let end = name.iter().position(|&b| b == 0).unwrap_or_else(|| name.len());
+            names.push(String::from_utf8_lossy(&name[..end]).into_owned());
+        }
+        Ok(names)
+    }
+
+    // From the texture header, which is little endian once loaded.
+    pub fn texture_count(&self) -> u16 {
+        (&self.texture[10..]).read_u16::<LE>().unwrap_or(0)
+    }
+
+    // Model data as it was before being rebased for the game.
+    pub fn raw_model(&self) -> Result<Vec<u8>> {
+        let mut model = self.model.clone();
+        let obj_offset = (&model[..]).read_u32::<LE>()?;
+        unrebase_njs_obj(&mut model, obj_offset as usize, self.model.as_ptr() as u32)
+            .map_err(|e| rebase_error("model", e.to_string()))?;
+        Ok(model)
+    }
+
+    pub fn raw_texlist(&self) -> Result<Vec<u8>> {
+        let mut texlist = self.texlist.clone();
+        let texlist_offset = (&texlist[..]).read_u32::<LE>()?;
+        unrebase_njs_texlist(&mut texlist, texlist_offset as usize, self.texlist.as_ptr() as u32)
+            .map_err(|e| rebase_error("texlist", e.to_string()))?;
+        Ok(texlist)
+    }
+
+    pub fn raw_texture(&self) -> Vec<u8> {
+        let mut texture = self.texture.clone();
+        swap_texture_header(&mut texture);
+        texture
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        DlcWriter::from_value(self)?.layout()
+    }
+}
+
+impl PartialEq for DlcModelData {
+    // The rebased pointers depend on where the data was allocated, so compare
+    // the on-disk form instead.
+    fn eq(&self, other: &DlcModelData) -> bool {
+        match (self.to_bytes(), other.to_bytes()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+#[derive(PartialEq)]
 pub struct DlcPrsData {
     pub kart_dlc: KartDlc,
-    pub set_data: Vec<u8>,
+    pub set_file: SetFile,
     pub track_data: Vec<u8>,
     pub model_data: DlcModelData,
 }
 
 impl DlcRead for DlcPrsData {
-    fn read_from<R>(mut read: R) -> io::Result<Self>
+    fn read_from<R>(read: &mut DlcReader<R>) -> Result<Self>
     where
         R: Read + Seek,
     {
-        let kart_dlc = Pointer::<KartDlc>::read_from(&mut read)?.0;
-        let mut set_data = OffsetLen::read_from(&mut read)?.0;
-        let mut offset = 32;
-        while offset < set_data.len() {
-            set_data.swap(offset + 0, offset + 1);
-            set_data.swap(offset + 2, offset + 3);
-            set_data.swap(offset + 4, offset + 5);
-            set_data.swap(offset + 6, offset + 7);
+        let kart_dlc = read.field::<Pointer<KartDlc>, _>("kart_dlc")?.0;
+        let set_data = read.field::<OffsetLen, _>("set_data")?.0;
+        read.path.push("set_data".to_string());
+        let set_file = SetFile::from_be_bytes(&set_data).map_err(|e| match e {
+            Error::Malformed { reason, .. } => read.error(reason),
+            e => read.annotate(e),
+        });
+        read.path.pop();
+        let set_file = set_file?;
+        let track_data = read.field::<OffsetLen, _>("track_data")?.0;
+        let model_data = read.field::<OffsetLen, _>("model_data")?.0;
+        read.path.push("model_data".to_string());
+        let model = read.nested(Cursor::new(model_data))
+            .and_then(|mut model_read| DlcModelData::read_from(&mut model_read));
+        read.path.pop();
+        let model = model?;
 
-            set_data.swap(offset + 8, offset + 11);
-            set_data.swap(offset + 9, offset + 10);
+        Ok(DlcPrsData {
+            kart_dlc: kart_dlc,
+            set_file: set_file,
+            track_data: track_data,
+            model_data: model,
+        })
+    }
+}
 
-            set_data.swap(offset + 12, offset + 15);
-            set_data.swap(offset + 13, offset + 14);
+impl DlcWrite for DlcPrsData {
+    fn write_to(&self, write: &mut DlcWriter) -> Result<()> {
+        Pointer(&self.kart_dlc).write_to(write)?;
+        OffsetLen(self.set_file.to_be_bytes()?).write_to(write)?;
+        OffsetLen(&self.track_data).write_to(write)?;
+        OffsetLen(self.model_data.to_bytes()?).write_to(write)
+    }
+}
 
-            set_data.swap(offset + 16, offset + 19);
-            set_data.swap(offset + 17, offset + 18);
+impl DlcPrsData {
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        DlcWriter::from_value(self)?.layout()
+    }
 
-            set_data.swap(offset + 20, offset + 23);
-            set_data.swap(offset + 21, offset + 22);
+    // Regenerates the compressed blob the DLC header's PRS pointer refers to.
+    pub fn to_prs(&self, compression: Compression) -> Result<Vec<u8>> {
+        Ok(prs::encode(&self.to_bytes()?, compression))
+    }
+}
 
-            set_data.swap(offset + 24, offset + 27);
-            set_data.swap(offset + 25, offset + 26);
+// Fields every DLC file has ahead of its PRS payload.
+pub struct DlcInfo {
+    pub header: VmsHeader,
+    pub dlc_type: u32,
+    pub dlc_texts: [DlcText; 6],
+    // Only the first seems to matter; kart DLC put the kart stage there.
+    pub level_ids: [LevelId; 8],
+}
 
-            set_data.swap(offset + 28, offset + 31);
-            set_data.swap(offset + 29, offset + 30);
+impl DlcInfo {
+    pub fn kart_stage(&self) -> LevelId {
+        self.level_ids[0]
+    }
 
-            offset += 32;
+    pub fn set_kart_stage(&mut self, level: LevelId) -> Result<()> {
+        if !level.is_valid() {
+            return Err(Error::Malformed {
+                path: "levels[0]".to_string(),
+                reason: format!("unknown level ID {}", level.0),
+            });
         }
-        let track_data = OffsetLen::read_from(&mut read)?.0;
-        let model_data = OffsetLen::read_from(&mut read)?.0;
-        let model = DlcModelData::read_from(Cursor::new(model_data))?;
+        self.level_ids[0] = level;
+        Ok(())
+    }
 
-        Ok(DlcPrsData {
-            kart_dlc: kart_dlc,
-            set_data: set_data,
-            track_data: track_data,
-            model_data: model,
-        })
+    // The game loads into the first level, so that one has to be known. The
+    // rest are kept as they are.
+    pub fn check_level_ids(level_ids: &[LevelId; 8], path: &str) -> Result<()> {
+        for (idx, level) in level_ids.iter().enumerate() {
+            if level.is_valid() {
+                continue;
+            }
+            let reason = format!("unknown level ID {}", level.0);
+            if idx == 0 {
+                return Err(Error::Malformed {
+                    path: format!("{}[0]", path),
+                    reason: reason,
+                });
+            }
+            warn!("{}[{}]: {}", path, idx, reason);
+        }
+        Ok(())
+    }
+
+    // Fills in the event table fields that are understood. The unknown ones
+    // are left as the game had them.
+    pub fn write_descriptor(&self, descriptor: &mut DlcDescriptor) {
+        descriptor.event_id = 1;
+        descriptor.dlc_type = self.dlc_type;
+        descriptor.levels = self.level_ids;
+        descriptor.text = self.dlc_texts;
     }
 }
 
-pub struct DlcData {
-    pub dlc_type: u32,
-    pub dlc_texts: [DlcText; 6],
-    pub level_ids: [u32; 8],
-    pub prs_data: DlcPrsData,
+// The VMS header is container metadata (the CRC and length change on every
+// write), so only the DLC contents are compared.
+impl PartialEq for DlcInfo {
+    fn eq(&self, other: &DlcInfo) -> bool {
+        self.dlc_type == other.dlc_type
+            && self.dlc_texts[..] == other.dlc_texts[..]
+            && self.level_ids == other.level_ids
+    }
+}
+
+// Only a handful of these are ever alive, so the small unlock key variant
+// isn't worth boxing the others for.
+#[allow(clippy::large_enum_variant)]
+pub enum DlcData {
+    Kart {
+        info: DlcInfo,
+        prs_data: DlcPrsData,
+    },
+    // Recognised, not supported. The PC code has a case for 0x04 next to
+    // karts, but no 0x04 file has been taken apart, so there is no layout to
+    // parse the payload into. The fields shared with karts are read; the PRS
+    // payload is only decoded and kept as-is so the file round-trips. These
+    // never get an event. Typed fields need a sample file to work from.
+    Type4 {
+        info: DlcInfo,
+        payload: Vec<u8>,
+    },
+    // Non-kart DLC just carry an 8-digit ASCII number where the type goes.
+    // Whatever follows it is kept untouched.
+    UnlockKey {
+        header: VmsHeader,
+        key: u32,
+        data: Vec<u8>,
+    },
 }
 
 impl DlcData {
-    pub fn from_vmu<R>(mut read: R) -> io::Result<DlcData>
+    pub const KART_TYPE: u32 = 0x03;
+    pub const TYPE4_TYPE: u32 = 0x04;
+    pub const UNLOCK_KEY_DIGITS: usize = 8;
+
+    // Same as from_vmu, but rejects files whose VMS CRC doesn't match.
+    pub fn from_vmu_strict<R>(mut read: R) -> Result<DlcData>
+    where
+        R: Read,
+    {
+        let mut file = Vec::new();
+        read.read_to_end(&mut file)?;
+        vms::verify_crc(&file)?;
+        DlcData::from_vmu(Cursor::new(file))
+    }
+
+    pub fn from_vmu<R>(mut read: R) -> Result<DlcData>
     where
         R: Read + Seek,
     {
-        read.seek(SeekFrom::Start(0x48))?;
-        let len = read.read_u32::<LE>()?;
-        read.seek(SeekFrom::Start(0x280))?;
-        let mut data: Vec<u8> = iter::repeat(0).take(len as usize).collect();
+        let header = VmsHeader::read_from(&mut read)?;
+        let file_len = read.seek(SeekFrom::End(0))?;
+        let data_end = header.header_len() as u64 + header.data_len as u64;
+        if data_end > file_len {
+            return Err(Error::VmsHeader(format!("data ends at 0x{:x} but the file is only 0x{:x} bytes", data_end, file_len)));
+        }
+        read.seek(SeekFrom::Start(header.header_len() as u64))?;
+        let mut data: Vec<u8> = iter::repeat(0).take(header.data_len as usize).collect();
         read.read_exact(&mut data)?;
-        let mut vmu_data = Cursor::new(data);
+        if let Some(key) = parse_unlock_key(&data) {
+            return Ok(DlcData::unlock_key_from_data(header, key, data));
+        }
+        let mut vmu_data = DlcReader::new(Cursor::new(data), "vmu")?;
 
-        let dlc_type = vmu_data.read_u32::<LE>()?;
+        let dlc_type = vmu_data.read_u32::<LE>().map_err(|e| vmu_data.annotate(e.into()))?;
+        if dlc_type != DlcData::KART_TYPE && dlc_type != DlcData::TYPE4_TYPE {
+            return Err(Error::Malformed {
+                path: "vmu.type".to_string(),
+                reason: format!("unknown DLC type 0x{:x}", dlc_type),
+            });
+        }
 
         let mut dlc_texts = [DlcText::default(); 6];
-        for idx in 0..5 {
-            let text = VmuPointer::<DlcText>::read_from(&mut vmu_data)?;
-            dlc_texts[idx] = text.0;
+        for (idx, text) in dlc_texts[..5].iter_mut().enumerate() {
+            *text = vmu_data.field::<VmuPointer<DlcText>, _>(format!("texts[{}]", idx))?.0;
         }
 
-        let mut level_ids = [0; 8];
-        for idx in 0..8 {
-            level_ids[idx] = vmu_data.read_u32::<LE>()?;
+        let mut level_ids = [LevelId::default(); 8];
+        for level in level_ids.iter_mut() {
+            *level = LevelId(vmu_data.read_u32::<LE>().map_err(|e| vmu_data.annotate(e.into()))?);
         }
+        DlcInfo::check_level_ids(&level_ids, "vmu.levels")?;
 
-        let prs_pointer = vmu_data.read_u32::<LE>()? - SAVE_BASE;
+        let prs_vmu_pointer = vmu_data.read_u32::<LE>().map_err(|e| vmu_data.annotate(e.into()))?;
+        let prs_pointer = prs_vmu_pointer.checked_sub(SAVE_BASE)
+            .filter(|&addr| (addr as u64) < vmu_data.len)
+            .ok_or_else(|| Error::BadPointer {
+                path: "vmu.prs".to_string(),
+                pointer: prs_vmu_pointer,
+            })?;
         vmu_data.seek(SeekFrom::Start(prs_pointer as u64))?;
 
-        let mut decoder = Decoder::new(vmu_data);
-        let decoded = decoder.decode_to_vec()?;
+        let mut decoder = Decoder::new(&mut vmu_data);
+        let decoded = decoder.decode_to_vec()
+            .map_err(Error::PrsDecode)?;
 
-        println!("Dlc type: {}", dlc_type);
+        debug!("DLC type: {}, decoded payload 0x{:x} bytes", dlc_type, decoded.len());
 
-        for (idx, text) in dlc_texts.iter().enumerate() {
-            println!("TEXT {}", idx);
-            dump_hex(&text.title);
-            dump_hex(&text.dlc_type);
-            dump_hex(&text.stage);
-            dump_hex(&text.character);
-            dump_hex(&text.description);
-        }
-
-        dump_hex(&decoded);
+        if log_enabled!(Level::Trace) {
+            for (idx, text) in dlc_texts.iter().enumerate() {
+                trace!("TEXT {}", idx);
+                dump_hex(&text.title);
+                dump_hex(&text.dlc_type);
+                dump_hex(&text.stage);
+                dump_hex(&text.character);
+                dump_hex(&text.description);
+            }
 
-        let prs_data = DlcPrsData::read_from(Cursor::new(decoded))?;
+            dump_hex(&decoded);
+        }
 
-        Ok(DlcData {
+        let info = DlcInfo {
+            header: header,
             dlc_type: dlc_type,
             dlc_texts: dlc_texts,
             level_ids: level_ids,
+        };
+
+        if dlc_type == DlcData::TYPE4_TYPE {
+            return Ok(DlcData::Type4 {
+                info: info,
+                payload: decoded,
+            });
+        }
+
+        let prs_data = DlcPrsData::read_from(&mut DlcReader::new(Cursor::new(decoded), "prs")?)?;
+
+        Ok(DlcData::Kart {
+            info: info,
             prs_data: prs_data,
         })
     }
+
+    fn unlock_key_from_data(header: VmsHeader, key: u32, mut data: Vec<u8>) -> DlcData {
+        debug!("Unlock key: {:08}", key);
+        let rest = data.split_off(DlcData::UNLOCK_KEY_DIGITS);
+        DlcData::UnlockKey {
+            header: header,
+            key: key,
+            data: rest,
+        }
+    }
+
+    pub fn to_vmu<W>(&self, write: W, compression: Compression) -> Result<()>
+    where
+        W: Write,
+    {
+        let prs_data = match *self {
+            DlcData::Kart { ref prs_data, .. } => prs_data.to_prs(compression)?,
+            DlcData::Type4 { ref payload, .. } => prs::encode(payload, compression),
+            DlcData::UnlockKey { ref header, key, ref data } => {
+                let mut file = format!("{:08}", key).into_bytes();
+                file.extend_from_slice(data);
+                return write_vmu(write, header, file);
+            }
+        };
+        let info = self.info().unwrap();
+
+        let mut payload = DlcWriter::new();
+        payload.write_u32::<LE>(info.dlc_type)?;
+        for text in info.dlc_texts[..5].iter() {
+            VmuPointer(text).write_to(&mut payload)?;
+        }
+        for level_id in info.level_ids.iter() {
+            payload.write_u32::<LE>(level_id.0)?;
+        }
+        VmuPointer(&prs_data[..]).write_to(&mut payload)?;
+        let data = payload.layout()?;
+        write_vmu(write, &info.header, data)
+    }
+
+    pub fn header(&self) -> &VmsHeader {
+        match *self {
+            DlcData::Kart { ref info, .. } => &info.header,
+            DlcData::Type4 { ref info, .. } => &info.header,
+            DlcData::UnlockKey { ref header, .. } => header,
+        }
+    }
+
+    // Unlock keys have no texts or levels.
+    pub fn info(&self) -> Option<&DlcInfo> {
+        match *self {
+            DlcData::Kart { ref info, .. } => Some(info),
+            DlcData::Type4 { ref info, .. } => Some(info),
+            DlcData::UnlockKey { .. } => None,
+        }
+    }
+
+    pub fn info_mut(&mut self) -> Option<&mut DlcInfo> {
+        match *self {
+            DlcData::Kart { ref mut info, .. } => Some(info),
+            DlcData::Type4 { ref mut info, .. } => Some(info),
+            DlcData::UnlockKey { .. } => None,
+        }
+    }
+
+    pub fn kart(&self) -> Option<&DlcPrsData> {
+        match *self {
+            DlcData::Kart { ref prs_data, .. } => Some(prs_data),
+            _ => None,
+        }
+    }
+
+    pub fn kart_mut(&mut self) -> Option<&mut DlcPrsData> {
+        match *self {
+            DlcData::Kart { ref mut prs_data, .. } => Some(prs_data),
+            _ => None,
+        }
+    }
+
+    // An unlock key has no texts or levels. The game reads the type word out
+    // of the start of its digits, so that is what goes in dlc_type.
+    pub fn write_descriptor(&self, descriptor: &mut DlcDescriptor) {
+        match *self {
+            DlcData::Kart { ref info, .. } | DlcData::Type4 { ref info, .. } => info.write_descriptor(descriptor),
+            DlcData::UnlockKey { key, .. } => {
+                let digits = format!("{:08}", key);
+                descriptor.event_id = 1;
+                descriptor.dlc_type = LE::read_u32(digits.as_bytes());
+                descriptor.levels = [LevelId::default(); 8];
+                descriptor.text = [DlcText::default(); 6];
+            }
+        }
+    }
+
+    pub fn unlock_key(&self) -> Option<u32> {
+        match *self {
+            DlcData::UnlockKey { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for DlcData {
+    fn eq(&self, other: &DlcData) -> bool {
+        match (self, other) {
+            (&DlcData::Kart { info: ref info_a, prs_data: ref prs_a },
+                &DlcData::Kart { info: ref info_b, prs_data: ref prs_b }) => info_a == info_b && prs_a == prs_b,
+            (&DlcData::Type4 { info: ref info_a, payload: ref payload_a },
+                &DlcData::Type4 { info: ref info_b, payload: ref payload_b }) => info_a == info_b && payload_a == payload_b,
+            (&DlcData::UnlockKey { key: key_a, data: ref data_a, .. },
+                &DlcData::UnlockKey { key: key_b, data: ref data_b, .. }) => key_a == key_b && data_a == data_b,
+            _ => false,
+        }
+    }
+}
+
+// Writes an event for each DLC into game_table. If there are more than it
+// holds, game_table is left alone and a bigger copy is returned for the game
+// to use instead; its extra entries start out as copies of the game's first
+// entry, so the fields nobody understands still hold values the game wrote.
+pub fn write_event_table(game_table: &mut [DlcDescriptor], dlc_vec: &[DlcData]) -> Option<Vec<DlcDescriptor>> {
+    let mut relocated = if dlc_vec.len() > game_table.len() {
+        let template = game_table.first().cloned().unwrap_or_default();
+        let mut table = game_table.to_vec();
+        table.resize(dlc_vec.len(), template);
+        Some(table)
+    } else {
+        None
+    };
+    let table = match relocated {
+        Some(ref mut table) => &mut table[..],
+        None => &mut game_table[..],
+    };
+    for (descriptor, dlc_read) in table.iter_mut().zip(dlc_vec.iter()) {
+        dlc_read.write_descriptor(descriptor);
+    }
+    relocated
+}
+
+// Wraps data in a copy of header, fixing up the length and CRC.
+fn write_vmu<W>(mut write: W, header: &VmsHeader, data: Vec<u8>) -> Result<()>
+where
+    W: Write,
+{
+    let mut header = header.clone();
+    header.data_len = data.len() as u32;
+    let mut file = Vec::new();
+    header.write_to(&mut file)?;
+    file.extend_from_slice(&data);
+    // VMU files take up whole blocks
+    align(&mut file, vms::BLOCK_SIZE);
+    vms::update_crc(&mut file)?;
+
+    write.write_all(&file)?;
+    Ok(())
+}
+
+// Anything that doesn't start with all 8 digits goes on to be parsed by
+// type, and fails there if the type is unknown.
+fn parse_unlock_key(data: &[u8]) -> Option<u32> {
+    let digits = data.get(..DlcData::UNLOCK_KEY_DIGITS)?;
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    String::from_utf8_lossy(digits).parse().ok()
 }
 
 fn dump_hex(data: &[u8]) {
-    let mut offset = 0;
-    while data.len() - offset >= 0x10 {
-        print!("{:08x} | ", offset);
-        print!("{:02x} {:02x} {:02x} {:02x}  ",
-            data[offset + 0],
-            data[offset + 1],
-            data[offset + 2],
-            data[offset + 3]
-        );
-        print!("{:02x} {:02x} {:02x} {:02x}  ",
-            data[offset + 4],
-            data[offset + 5],
-            data[offset + 6],
-            data[offset + 7]
-        );
-        print!("{:02x} {:02x} {:02x} {:02x}  ",
-            data[offset + 8],
-            data[offset + 9],
-            data[offset + 10],
-            data[offset + 11]
-        );
-        print!("{:02x} {:02x} {:02x} {:02x} | ",
-            data[offset + 12],
-            data[offset + 13],
-            data[offset + 14],
-            data[offset + 15]
-        );
-        for idx in 0..0x10 {
-            let val = data[offset + idx];
+    for (idx, chunk) in data.chunks(0x10).enumerate() {
+        if chunk.len() < 0x10 {
+            trace!("Plus {} more bytes.", chunk.len());
+            break;
+        }
+        let mut line = format!("{:08x} |", idx * 0x10);
+        for group in chunk.chunks(4) {
+            line.push(' ');
+            for byte in group {
+                line.push_str(&format!("{:02x} ", byte));
+            }
+        }
+        line.push_str("| ");
+        for &val in chunk {
             if val >= 0x20 && val <= 0x7e {
-                print!("{}", val as char);
+                line.push(val as char);
             } else {
-                print!(".");
+                line.push('.');
             }
         }
-        println!();
-        offset += 0x10;
+        trace!("{}", line);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::mem;
+
+    use super::*;
+    use crate::model::AiKart;
+    use crate::vms::{EyecatchType, ICON_SIZE};
+
+    pub(crate) fn sample_header() -> VmsHeader {
+        let mut vmu_description = [b' '; 16];
+        vmu_description[..4].copy_from_slice(b"TEST");
+        VmsHeader {
+            vmu_description: vmu_description,
+            boot_description: [b' '; 32],
+            app_id: [0; 16],
+            anim_speed: 0,
+            eyecatch_type: EyecatchType::None,
+            crc: 0,
+            data_len: 0,
+            reserved: [0; 20],
+            palette: [0; 16],
+            icons: vec![[0x12; ICON_SIZE]],
+            eyecatch: Vec::new(),
+        }
+    }
+
+    pub(crate) fn sample_info(dlc_type: u32) -> DlcInfo {
+        let mut dlc_texts = [DlcText::default(); 6];
+        dlc_texts[0].title[..4].copy_from_slice(b"Kart");
+        dlc_texts[1].description = [b'x'; 128];
+        dlc_texts[1].description[127] = 0;
+        let mut level_ids = [LevelId::default(); 8];
+        // dlc_documentation.txt: kart DLC put 0x46 here.
+        level_ids[0] = LevelId::ROUTE_101_280;
+        DlcInfo {
+            header: sample_header(),
+            dlc_type: dlc_type,
+            dlc_texts: dlc_texts,
+            level_ids: level_ids,
+        }
+    }
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        (&mut data[offset..]).write_u32::<LE>(value).unwrap();
+    }
+
+    // One object at 0x04 holding a model at 0x40, whose vertices start at 0x50.
+    fn sample_model() -> Vec<u8> {
+        let mut model = vec![0; 0x60];
+        put_u32(&mut model, 0x00, 0x04);
+        put_u32(&mut model, 0x04 + 0x04, 0x40);
+        put_u32(&mut model, 0x40, 0x50);
+        model
+    }
+
+    // One texture name entry at 0x0c, naming "kart" at 0x18.
+    fn sample_texlist() -> Vec<u8> {
+        let mut texlist = vec![0; 0x20];
+        put_u32(&mut texlist, 0x00, 0x04);
+        put_u32(&mut texlist, 0x04, 0x0c);
+        put_u32(&mut texlist, 0x08, 1);
+        put_u32(&mut texlist, 0x0c, 0x18);
+        texlist[0x18..0x1c].copy_from_slice(b"kart");
+        texlist
+    }
+
+    fn sample_texture() -> Vec<u8> {
+        (0..0xd0u32).map(|x| x as u8).collect()
+    }
+
+    fn sample_prs_data() -> DlcPrsData {
+        let mut kart_dlc = KartDlc {
+            stats: KartStats {
+                accel: 0.05,
+                brake_force: 0.1,
+                max_drive_speed: 2.0,
+                gravity: 0.06,
+                hard_speed_cap: 4.0,
+                ..KartStats::default()
+            },
+            autorun_slot_handicap_1: 1.0,
+            autorun_rank_handicap_1: 1.0,
+            autorun_not_first_handicap_1: 1.0,
+            autorun_slot_handicap_2: 1.0,
+            autorun_rank_handicap_2: 1.0,
+            autorun_not_first_handicap_2: 1.0,
+            ai_use_dlc_kart: AiKart::DlcKart.to_u32(),
+            song_name: [0; 64],
+        };
+        kart_dlc.set_song_name("a_mine.adx").unwrap();
+        // Two objects, with the count up front.
+        let mut set_data: Vec<u8> = (0..0x60u32).map(|x| x as u8).collect();
+        set_data[..4].copy_from_slice(&[0, 0, 0, 2]);
+        DlcPrsData {
+            kart_dlc: kart_dlc,
+            set_file: SetFile::from_be_bytes(&set_data).unwrap(),
+            track_data: vec![7; 13],
+            model_data: DlcModelData::from_raw(&sample_model(), &sample_texlist(), &sample_texture()).unwrap(),
+        }
+    }
+
+    pub(crate) fn sample_kart() -> DlcData {
+        DlcData::Kart {
+            info: sample_info(DlcData::KART_TYPE),
+            prs_data: sample_prs_data(),
+        }
+    }
+
+    fn layout<T>(value: &T) -> Vec<u8>
+    where
+        T: DlcWrite + ?Sized,
+    {
+        DlcWriter::from_value(value).unwrap().layout().unwrap()
+    }
+
+    fn read_back<T>(data: &[u8]) -> T
+    where
+        T: DlcRead,
+    {
+        T::read_from(&mut DlcReader::new(Cursor::new(data), "test").unwrap()).unwrap()
+    }
+
+    fn get_u32(data: &[u8], offset: usize) -> u32 {
+        (&data[offset..]).read_u32::<LE>().unwrap()
+    }
+
+    #[test]
+    fn pointer_round_trip() {
+        let stats = sample_prs_data().kart_dlc.stats;
+        let data = layout(&Pointer(&stats));
+        assert_eq!(data.len(), 4 + 0x28);
+        assert_eq!(get_u32(&data, 0), 4);
+        assert!(read_back::<Pointer<KartStats>>(&data).0 == stats);
+
+        // The inner pointer's target goes after the block holding it.
+        let data = layout(&Pointer(Pointer(&stats)));
+        assert_eq!(get_u32(&data, 0), 4);
+        assert_eq!(get_u32(&data, 4), 8);
+        assert!((read_back::<Pointer<Pointer<KartStats>>>(&data).0).0 == stats);
+    }
+
+    #[test]
+    fn vmu_pointer_round_trip() {
+        let mut name = [0; 128];
+        name[..3].copy_from_slice(b"abc");
+        let data = layout(&VmuPointer(&name));
+        assert_eq!(data, [0x04, 0x00, 0xb0, 0x8c, b'a', b'b', b'c', 0x00]);
+        assert!(read_back::<VmuPointer<[u8; 128]>>(&data).0[..] == name[..]);
+    }
+
+    #[test]
+    fn offset_len_alignment() {
+        let mut write = DlcWriter::new();
+        OffsetLen(&[1u8, 2, 3][..]).write_to(&mut write).unwrap();
+        OffsetLen(vec![4u8; 5]).write_to(&mut write).unwrap();
+        OffsetLen(&[][..]).write_to(&mut write).unwrap();
+        let data = write.layout().unwrap();
+        // Each block starts 4 byte aligned, padding after the one before.
+        assert_eq!((get_u32(&data, 0x00), get_u32(&data, 0x04)), (0x18, 3));
+        assert_eq!((get_u32(&data, 0x08), get_u32(&data, 0x0c)), (0x1c, 5));
+        assert_eq!((get_u32(&data, 0x10), get_u32(&data, 0x14)), (0x24, 0));
+        assert_eq!(&data[0x18..0x1c], [1, 2, 3, 0]);
+
+        let mut read = DlcReader::new(Cursor::new(&data[..]), "test").unwrap();
+        assert_eq!(read.field::<OffsetLen, _>("a").unwrap().0, [1, 2, 3]);
+        assert_eq!(read.field::<OffsetLen, _>("b").unwrap().0, [4; 5]);
+        assert!(read.field::<OffsetLen, _>("c").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn dlc_text_round_trip() {
+        let text = sample_info(DlcData::KART_TYPE).dlc_texts[1];
+        let data = layout(&VmuPointer(&text));
+        // The text block, then each string it points to, in field order.
+        assert_eq!(get_u32(&data, 0), SAVE_BASE + 4);
+        let mut expected = 4 + 5 * 4;
+        for idx in 0..5 {
+            let addr = get_u32(&data, 4 + 4 * idx) - SAVE_BASE;
+            assert_eq!(addr as usize, expected);
+            let len = if idx == 4 { 128 } else { 1 };
+            expected += (len + 3) / 4 * 4;
+        }
+        assert!(read_back::<VmuPointer<DlcText>>(&data).0 == text);
+    }
+
+    #[test]
+    fn kart_dlc_round_trip() {
+        let kart_dlc = sample_prs_data().kart_dlc;
+        let data = layout(&kart_dlc.stats);
+        assert_eq!(data.len(), mem::size_of::<KartStats>());
+        assert!(read_back::<KartStats>(&data) == kart_dlc.stats);
+
+        let data = layout(&kart_dlc);
+        assert_eq!(data.len(), mem::size_of::<KartDlc>());
+        assert!(read_back::<KartDlc>(&data) == kart_dlc);
+    }
+
+    #[test]
+    fn model_data_round_trip() {
+        let model_data = sample_prs_data().model_data;
+        let data = layout(&model_data);
+        assert_eq!((get_u32(&data, 0x00), get_u32(&data, 0x04)), (0x18, 0x60));
+        assert_eq!((get_u32(&data, 0x08), get_u32(&data, 0x0c)), (0x78, 0x20));
+        assert_eq!((get_u32(&data, 0x10), get_u32(&data, 0x14)), (0x98, 0xd0));
+
+        let read = read_back::<DlcModelData>(&data);
+        assert!(read == model_data);
+        assert_eq!(read.raw_model().unwrap(), sample_model());
+        assert_eq!(read.raw_texlist().unwrap(), sample_texlist());
+        assert_eq!(read.raw_texture(), sample_texture());
+        assert_eq!(read.texture_names().unwrap(), ["kart"]);
+        // Rebased against where the read copy lives.
+        assert_eq!(read.model_ptr, read.model.as_ptr() as u32 + 4);
+        assert_eq!(get_u32(&read.model, 0x40), read.model.as_ptr() as u32 + 0x50);
+    }
+
+    fn rebase_error_path(model: &[u8], texlist: &[u8]) -> String {
+        match DlcModelData::from_raw(model, texlist, &sample_texture()) {
+            Err(Error::ModelRebase { path, .. }) => path,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("rebased bad model data"),
+        }
+    }
+
+    #[test]
+    fn model_rebase_bounds() {
+        let mut model = sample_model();
+        put_u32(&mut model, 0x40, 0x60);
+        assert_eq!(rebase_error_path(&model, &sample_texlist()), "model_data.model@0x40.vertices");
+
+        let mut model = sample_model();
+        put_u32(&mut model, 0x04 + 0x2c, 0xffff_fff0);
+        assert_eq!(rebase_error_path(&model, &sample_texlist()), "model_data.model@0x4.child");
+
+        // An object that is its own sibling sees its model pointer already
+        // rebased on the second visit.
+        let mut model = sample_model();
+        put_u32(&mut model, 0x04 + 0x30, 0x04);
+        assert_eq!(rebase_error_path(&model, &sample_texlist()), "model_data.model@0x4.model");
+
+        let mut model = sample_model();
+        put_u32(&mut model, 0x00, 0x5c);
+        assert_eq!(rebase_error_path(&model, &sample_texlist()), "model_data.model@0x5c.model");
+
+        let mut texlist = sample_texlist();
+        put_u32(&mut texlist, 0x0c, 0x20);
+        assert_eq!(rebase_error_path(&sample_model(), &texlist), "model_data.texlist@0xc.filename");
+
+        let mut texlist = sample_texlist();
+        put_u32(&mut texlist, 0x08, 0xffff_ffff);
+        assert_eq!(rebase_error_path(&sample_model(), &texlist), "model_data.texlist@0x18.filename");
+    }
+
+    #[test]
+    fn prs_data_round_trip() {
+        let prs_data = sample_prs_data();
+        let data = prs_data.to_bytes().unwrap();
+        // kart_dlc follows the 0x1c byte header, then the blobs in field order.
+        assert_eq!(get_u32(&data, 0x00), 0x1c);
+        assert_eq!((get_u32(&data, 0x04), get_u32(&data, 0x08)), (0x1c + 0x84, 0x60));
+        let track_offset = get_u32(&data, 0x0c) as usize;
+        assert_eq!(track_offset, 0x1c + 0x84 + 0x60);
+        assert_eq!(get_u32(&data, 0x10), 13);
+        let model_offset = get_u32(&data, 0x14);
+        assert_eq!(model_offset as usize, track_offset + 16);
+        // Offsets inside the model blob are relative to the blob.
+        assert_eq!(get_u32(&data, model_offset as usize), 0x18);
+
+        assert!(read_back::<DlcPrsData>(&data) == prs_data);
+    }
+
+    fn vmu_round_trip(dlc: &DlcData, compression: Compression) -> Vec<u8> {
+        let mut file = Vec::new();
+        dlc.to_vmu(&mut file, compression).unwrap();
+        assert_eq!(file.len() % vms::BLOCK_SIZE, 0);
+        vms::verify_crc(&file).unwrap();
+        let read = DlcData::from_vmu_strict(&file[..]).unwrap();
+        assert!(read == *dlc);
+        file
+    }
+
+    #[test]
+    fn kart_vmu_round_trip() {
+        let dlc = sample_kart();
+        let file = vmu_round_trip(&dlc, Compression::Max);
+        assert_eq!(vmu_round_trip(&dlc, Compression::Fast).len() % vms::BLOCK_SIZE, 0);
+        // Writing what was read back gives the same file.
+        let read = DlcData::from_vmu_strict(&file[..]).unwrap();
+        let mut rewritten = Vec::new();
+        read.to_vmu(&mut rewritten, Compression::Max).unwrap();
+        assert_eq!(rewritten, file);
+    }
+
+    #[test]
+    fn type4_vmu_round_trip() {
+        let dlc = DlcData::Type4 {
+            info: sample_info(DlcData::TYPE4_TYPE),
+            payload: vec![1, 2, 3, 4, 5],
+        };
+        vmu_round_trip(&dlc, Compression::Fast);
+    }
+
+    #[test]
+    fn unlock_key_vmu_round_trip() {
+        let dlc = DlcData::UnlockKey {
+            header: sample_header(),
+            key: 1234567,
+            data: vec![9; 20],
+        };
+        let file = vmu_round_trip(&dlc, Compression::Fast);
+        let header_len = dlc.header().header_len();
+        assert_eq!(&file[header_len..header_len + 8], b"01234567");
+    }
+
+    #[test]
+    fn vmu_crc_mismatch() {
+        let mut file = Vec::new();
+        sample_kart().to_vmu(&mut file, Compression::Fast).unwrap();
+        // Inside the VMU description, so the file still parses.
+        file[4] ^= 0xff;
+        assert!(vms::verify_crc(&file).is_err());
+        assert!(DlcData::from_vmu_strict(&file[..]).is_err());
+        assert!(DlcData::from_vmu(Cursor::new(&file[..])).is_ok());
+    }
+
+    fn game_entry(unknown: u32) -> DlcDescriptor {
+        DlcDescriptor {
+            unknown1: unknown,
+            unknown5: unknown + 1,
+            dlc_type: 0xff,
+            ..DlcDescriptor::default()
+        }
+    }
+
+    #[test]
+    fn event_table_in_place() {
+        let mut game_table = [game_entry(7), game_entry(8)];
+        let dlc_vec = [sample_kart()];
+        assert!(write_event_table(&mut game_table, &dlc_vec).is_none());
+        assert_eq!(game_table[0].event_id, 1);
+        assert_eq!(game_table[0].dlc_type, DlcData::KART_TYPE);
+        assert_eq!(game_table[0].unknown1, 7);
+        assert_eq!(game_table[0].levels[0], LevelId::ROUTE_101_280);
+        // Past the last DLC the game's entry is untouched.
+        assert_eq!(game_table[1].dlc_type, 0xff);
+    }
+
+    #[test]
+    fn event_table_relocated() {
+        let mut game_table = [game_entry(7), game_entry(8)];
+        let dlc_vec = [sample_kart(), sample_kart(), sample_kart()];
+        let table = write_event_table(&mut game_table, &dlc_vec).unwrap();
+        assert_eq!(table.len(), 3);
+        for descriptor in table.iter() {
+            assert_eq!(descriptor.event_id, 1);
+            assert_eq!(descriptor.dlc_type, DlcData::KART_TYPE);
+            assert!(descriptor.text[..] == sample_info(DlcData::KART_TYPE).dlc_texts[..]);
+        }
+        // The unknown fields come from the game's own entries.
+        assert_eq!((table[1].unknown1, table[1].unknown5), (8, 9));
+        assert_eq!((table[2].unknown1, table[2].unknown5), (7, 8));
+        // The game's table is left for the relocated one to replace.
+        assert_eq!(game_table[0].dlc_type, 0xff);
+        assert_eq!(game_table[1].dlc_type, 0xff);
+    }
+
+    #[test]
+    fn unlock_key_needs_eight_digits() {
+        assert_eq!(parse_unlock_key(b"01234567rest"), Some(1234567));
+        assert_eq!(parse_unlock_key(b"99999999"), Some(99999999));
+        assert_eq!(parse_unlock_key(b"1234"), None);
+        assert_eq!(parse_unlock_key(b"1234\x03\0\0\0"), None);
+        assert_eq!(parse_unlock_key(b"0123456x"), None);
+    }
+
+    #[test]
+    fn four_digits_arent_a_key() {
+        let mut file = Vec::new();
+        write_vmu(&mut file, &sample_header(), b"1234\x03\0\0\0".to_vec()).unwrap();
+        let e = DlcData::from_vmu(Cursor::new(&file[..])).err().unwrap();
+        assert_eq!(e.to_string(), "vmu.type: unknown DLC type 0x34333231");
+    }
+
+    #[test]
+    fn unlock_key_descriptor() {
+        let mut game_table = [game_entry(7), game_entry(8)];
+        game_table[1].levels[0] = LevelId::ROUTE_101_280;
+        let dlc_vec = [sample_kart(), DlcData::UnlockKey {
+            header: sample_header(),
+            key: 1234567,
+            data: Vec::new(),
+        }];
+        assert!(write_event_table(&mut game_table, &dlc_vec).is_none());
+        assert_eq!(game_table[0].dlc_type, DlcData::KART_TYPE);
+        let key = &game_table[1];
+        assert_eq!(key.event_id, 1);
+        assert_eq!(key.dlc_type, LE::read_u32(b"0123"));
+        assert_eq!(key.levels, [LevelId::default(); 8]);
+        assert!(key.text[..] == [DlcText::default(); 6][..]);
+        assert_eq!(key.unknown1, 8);
+    }
+
+    #[test]
+    fn check_level_ids() {
+        let mut level_ids = [LevelId::default(); 8];
+        level_ids[0] = LevelId(0x46);
+        assert_eq!(level_ids[0], LevelId::ROUTE_101_280);
+        DlcInfo::check_level_ids(&level_ids, "levels").unwrap();
+        level_ids[0] = LevelId::KART_RACE;
+        DlcInfo::check_level_ids(&level_ids, "levels").unwrap();
+
+        // Only the first one has to be known.
+        level_ids[3] = LevelId(68);
+        DlcInfo::check_level_ids(&level_ids, "levels").unwrap();
+        level_ids[0] = LevelId(68);
+        let e = DlcInfo::check_level_ids(&level_ids, "levels").err().unwrap();
+        assert_eq!(e.to_string(), "levels[0]: unknown level ID 68");
     }
-    println!("Plus {} more bytes.", data.len() - offset);
 }
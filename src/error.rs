@@ -0,0 +1,105 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    VmsHeader(String),
+    VmsCrc {
+        stored: u16,
+        computed: u16,
+    },
+    PrsDecode(io::Error),
+    OutOfRange {
+        path: String,
+        offset: u64,
+        len: u64,
+        size: u64,
+    },
+    BadPointer {
+        path: String,
+        pointer: u32,
+    },
+    Malformed {
+        path: String,
+        reason: String,
+    },
+    ModelRebase {
+        path: String,
+        reason: String,
+    },
+    Text {
+        field: String,
+        reason: String,
+    },
+    Config(String),
+    Export(String),
+    OpenProcess {
+        pid: u32,
+        error: io::Error,
+    },
+    ProcessName(String),
+    EnumProcesses(io::Error),
+    MemoryRead {
+        address: u32,
+        len: usize,
+    },
+    MemoryWrite {
+        address: u32,
+        len: usize,
+    },
+    PatchMismatch {
+        address: u32,
+        expected: Vec<u8>,
+        found: Vec<u8>,
+    },
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::VmsHeader(ref reason) => write!(f, "bad VMS header: {}", reason),
+            Error::VmsCrc { stored, computed } =>
+                write!(f, "VMS CRC mismatch: header says 0x{:04x}, data gives 0x{:04x}", stored, computed),
+            Error::PrsDecode(ref e) => write!(f, "prs: decoding failed: {}", e),
+            Error::OutOfRange { ref path, offset, len, size } =>
+                write!(f, "{}: offset 0x{:x} + len 0x{:x} exceeds 0x{:x}", path, offset, len, size),
+            Error::BadPointer { ref path, pointer } =>
+                write!(f, "{}: pointer 0x{:08x} is outside the data", path, pointer),
+            Error::Malformed { ref path, ref reason } => write!(f, "{}: {}", path, reason),
+            Error::ModelRebase { ref path, ref reason } => write!(f, "{}: rebase failed: {}", path, reason),
+            Error::Text { ref field, ref reason } => write!(f, "{}: {}", field, reason),
+            Error::Config(ref reason) => write!(f, "bad config: {}", reason),
+            Error::Export(ref reason) => write!(f, "bad export: {}", reason),
+            Error::OpenProcess { pid, ref error } => write!(f, "could not open process {}: {}", pid, error),
+            Error::ProcessName(ref reason) => write!(f, "could not get process name: {}", reason),
+            Error::EnumProcesses(ref e) => write!(f, "could not list processes: {}", e),
+            Error::MemoryRead { address, len } =>
+                write!(f, "could not read 0x{:x} bytes at 0x{:08x}", len, address),
+            Error::MemoryWrite { address, len } =>
+                write!(f, "could not write 0x{:x} bytes at 0x{:08x}", len, address),
+            Error::PatchMismatch { address, ref expected, ref found } =>
+                write!(f, "patch at 0x{:08x} didn't stick: expected {:02x?}, found {:02x?}", address, expected, found),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) | Error::PrsDecode(ref e) | Error::EnumProcesses(ref e) => Some(e),
+            Error::OpenProcess { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
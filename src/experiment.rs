@@ -0,0 +1,343 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
+
+use log::debug;
+
+use crate::error::{Error, Result};
+use crate::model::{DlcDescriptor, KartStats};
+#[cfg(windows)]
+use crate::process_reader::ProcessHandle;
+
+// Where sweeps read and write. The game process is one backend; a plain
+// buffer stands in for it when replaying or checking a sweep offline.
+pub trait Memory {
+    fn read(&self, address: u32, buf: &mut [u8]) -> Result<()>;
+    fn write(&self, address: u32, data: &[u8]) -> Result<()>;
+}
+
+#[cfg(windows)]
+impl Memory for ProcessHandle {
+    fn read(&self, address: u32, buf: &mut [u8]) -> Result<()> {
+        if self.read_data(address, buf)? != buf.len() {
+            return Err(Error::MemoryRead {
+                address: address,
+                len: buf.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<()> {
+        if self.write_data(address, data)? != data.len() {
+            return Err(Error::MemoryWrite {
+                address: address,
+                len: data.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+pub struct BufferMemory {
+    pub base: u32,
+    pub data: RefCell<Vec<u8>>,
+}
+
+impl BufferMemory {
+    pub fn new(base: u32, data: Vec<u8>) -> BufferMemory {
+        BufferMemory {
+            base: base,
+            data: RefCell::new(data),
+        }
+    }
+
+    fn range(&self, address: u32, len: usize) -> Option<(usize, usize)> {
+        let start = address.checked_sub(self.base)? as usize;
+        let end = start.checked_add(len)?;
+        if end <= self.data.borrow().len() {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+}
+
+impl Memory for BufferMemory {
+    fn read(&self, address: u32, buf: &mut [u8]) -> Result<()> {
+        let (start, end) = self.range(address, buf.len())
+            .ok_or(Error::MemoryRead {
+                address: address,
+                len: buf.len(),
+            })?;
+        buf.copy_from_slice(&self.data.borrow()[start..end]);
+        Ok(())
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<()> {
+        let (start, end) = self.range(address, data.len())
+            .ok_or(Error::MemoryWrite {
+                address: address,
+                len: data.len(),
+            })?;
+        self.data.borrow_mut()[start..end].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+// Fields whose purpose is still unknown. Everything is 4 bytes wide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownField {
+    KartStatsUnknown1,
+    KartStatsUnknown2,
+    DescriptorUnknown1,
+    DescriptorUnknown2,
+    DescriptorUnknown3,
+    DescriptorUnknown4,
+    DescriptorUnknown5,
+}
+
+impl UnknownField {
+    pub const ALL: [UnknownField; 7] = [
+        UnknownField::KartStatsUnknown1,
+        UnknownField::KartStatsUnknown2,
+        UnknownField::DescriptorUnknown1,
+        UnknownField::DescriptorUnknown2,
+        UnknownField::DescriptorUnknown3,
+        UnknownField::DescriptorUnknown4,
+        UnknownField::DescriptorUnknown5,
+    ];
+
+    // Offset from the start of the owning struct: KartStats (which starts
+    // KartDlc) or DlcDescriptor.
+    pub fn offset(self) -> u32 {
+        match self {
+            UnknownField::KartStatsUnknown1 => KartStats::UNKNOWN1_OFFSET,
+            UnknownField::KartStatsUnknown2 => KartStats::UNKNOWN2_OFFSET,
+            UnknownField::DescriptorUnknown1 => DlcDescriptor::UNKNOWN1_OFFSET,
+            UnknownField::DescriptorUnknown2 => DlcDescriptor::UNKNOWN2_OFFSET,
+            UnknownField::DescriptorUnknown3 => DlcDescriptor::UNKNOWN3_OFFSET,
+            UnknownField::DescriptorUnknown4 => DlcDescriptor::UNKNOWN4_OFFSET,
+            UnknownField::DescriptorUnknown5 => DlcDescriptor::UNKNOWN5_OFFSET,
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        match self {
+            UnknownField::KartStatsUnknown1 | UnknownField::KartStatsUnknown2 => true,
+            _ => false,
+        }
+    }
+}
+
+// One field swept over a list of values, with everything else left as is.
+pub struct Sweep {
+    pub field: UnknownField,
+    // Address of the owning struct.
+    pub base: u32,
+    // Raw bits; see float_steps for f32 fields.
+    pub values: Vec<u32>,
+    // Regions read back after each value settles.
+    pub watch: Vec<(u32, usize)>,
+}
+
+pub struct Change {
+    pub address: u32,
+    pub before: u8,
+    pub after: u8,
+}
+
+pub struct Sample {
+    pub value: u32,
+    pub changes: Vec<Change>,
+}
+
+// Evenly spaced f32 values from start to end inclusive, as raw bits.
+pub fn float_steps(start: f32, end: f32, steps: usize) -> Vec<u32> {
+    if steps < 2 {
+        return vec![start.to_bits()];
+    }
+    (0..steps)
+        .map(|idx| start + (end - start) * idx as f32 / (steps - 1) as f32)
+        .map(f32::to_bits)
+        .collect()
+}
+
+// Writes each value in turn, lets settle run (wait a few frames, poke the
+// game along, ...) and records how the watched regions differ from how they
+// looked before the sweep. The original value is put back afterwards, even
+// if a step fails.
+pub fn run_sweep<M, F>(memory: &M, sweep: &Sweep, mut settle: F) -> Result<Vec<Sample>>
+where
+    M: Memory,
+    F: FnMut(&M) -> Result<()>,
+{
+    let address = sweep.base.checked_add(sweep.field.offset())
+        .ok_or_else(|| Error::Config(
+            format!("{:?} at base 0x{:08x} is past the end of memory", sweep.field, sweep.base)))?;
+    let mut original = [0; 4];
+    memory.read(address, &mut original)?;
+
+    let mut baseline = Vec::with_capacity(sweep.watch.len());
+    for &(watch_address, len) in sweep.watch.iter() {
+        if u32::try_from(len).ok().and_then(|len| watch_address.checked_add(len)).is_none() {
+            return Err(Error::Config(
+                format!("watch region 0x{:08x}+0x{:x} is past the end of memory", watch_address, len)));
+        }
+        let mut region = vec![0; len];
+        memory.read(watch_address, &mut region)?;
+        baseline.push(region);
+    }
+
+    let res = sweep.values.iter()
+        .map(|&value| {
+            memory.write(address, &value.to_le_bytes())?;
+            settle(memory)?;
+            let mut changes = Vec::new();
+            for (&(watch_address, len), before) in sweep.watch.iter().zip(baseline.iter()) {
+                let mut after = vec![0; len];
+                memory.read(watch_address, &mut after)?;
+                for (idx, (&old, &new)) in before.iter().zip(after.iter()).enumerate() {
+                    if old != new {
+                        changes.push(Change {
+                            address: watch_address + idx as u32,
+                            before: old,
+                            after: new,
+                        });
+                    }
+                }
+            }
+            debug!("{:?} = 0x{:08x}: {} byte(s) changed", sweep.field, value, changes.len());
+            Ok(Sample {
+                value: value,
+                changes: changes,
+            })
+        })
+        .collect();
+
+    memory.write(address, &original)?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u32 = 0x1000;
+    // Where the fake game reacts to the swept field.
+    const EFFECT: u32 = BASE + 0x30;
+
+    fn sweep(field: UnknownField, values: Vec<u32>) -> Sweep {
+        Sweep {
+            field: field,
+            base: BASE,
+            values: values,
+            watch: vec![(EFFECT, 4)],
+        }
+    }
+
+    fn read_u32(memory: &BufferMemory, address: u32) -> u32 {
+        let mut buf = [0; 4];
+        memory.read(address, &mut buf).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    // Copies the low byte of the swept field to EFFECT, like a frame of the
+    // game reading it.
+    fn settle(field: UnknownField) -> impl FnMut(&BufferMemory) -> Result<()> {
+        move |memory| {
+            let value = read_u32(memory, BASE + field.offset());
+            memory.write(EFFECT, &[value as u8])
+        }
+    }
+
+    #[test]
+    fn offsets_match_the_layout() {
+        let stats = KartStats::default();
+        let stats_base = &stats as *const KartStats as usize;
+        assert_eq!(&stats.unknown1 as *const f32 as usize - stats_base, 0x14);
+        assert_eq!(&stats.unknown2 as *const f32 as usize - stats_base, 0x20);
+
+        let descriptor = DlcDescriptor::default();
+        let descriptor_base = &descriptor as *const DlcDescriptor as usize;
+        let offsets = [
+            &descriptor.unknown1 as *const u32 as usize - descriptor_base,
+            &descriptor.unknown2 as *const u32 as usize - descriptor_base,
+            &descriptor.unknown3 as *const u32 as usize - descriptor_base,
+            &descriptor.unknown4 as *const u32 as usize - descriptor_base,
+            &descriptor.unknown5 as *const u32 as usize - descriptor_base,
+        ];
+        assert_eq!(offsets, [0x0, 0x8, 0xc, 0x10, 0x14]);
+
+        let fields: Vec<_> = UnknownField::ALL.iter().map(|field| field.offset()).collect();
+        assert_eq!(fields, vec![0x14, 0x20, 0x0, 0x8, 0xc, 0x10, 0x14]);
+    }
+
+    #[test]
+    fn sweep_records_changes() {
+        let field = UnknownField::KartStatsUnknown2;
+        let memory = BufferMemory::new(BASE, vec![0; 0x40]);
+        memory.write(BASE + field.offset(), &0xabu32.to_le_bytes()).unwrap();
+        let samples = run_sweep(&memory, &sweep(field, vec![0, 5]), settle(field)).unwrap();
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].value, 0);
+        assert!(samples[0].changes.is_empty());
+        assert_eq!(samples[1].value, 5);
+        assert_eq!(samples[1].changes.len(), 1);
+        let change = &samples[1].changes[0];
+        assert_eq!((change.address, change.before, change.after), (EFFECT, 0, 5));
+        assert_eq!(read_u32(&memory, BASE + field.offset()), 0xab);
+    }
+
+    #[test]
+    fn sweep_restores_after_settle_fails() {
+        let field = UnknownField::DescriptorUnknown3;
+        let memory = BufferMemory::new(BASE, vec![0; 0x40]);
+        memory.write(BASE + field.offset(), &0x1234u32.to_le_bytes()).unwrap();
+        let mut calls = 0;
+        let res = run_sweep(&memory, &sweep(field, vec![1, 2, 3]), |_| {
+            calls += 1;
+            if calls == 2 {
+                Err(Error::Config("game went away".to_string()))
+            } else {
+                Ok(())
+            }
+        });
+
+        assert_eq!(res.err().unwrap().to_string(), "bad config: game went away");
+        assert_eq!(calls, 2);
+        assert_eq!(read_u32(&memory, BASE + field.offset()), 0x1234);
+    }
+
+    #[test]
+    fn sweep_rejects_overflowing_addresses() {
+        let memory = BufferMemory::new(BASE, vec![0; 0x40]);
+        let mut bad_base = sweep(UnknownField::KartStatsUnknown2, vec![1]);
+        bad_base.base = u32::max_value() - 4;
+        assert!(run_sweep(&memory, &bad_base, |_| Ok(())).is_err());
+
+        let mut bad_watch = sweep(UnknownField::KartStatsUnknown2, vec![1]);
+        bad_watch.watch = vec![(u32::max_value() - 2, 4)];
+        assert!(run_sweep(&memory, &bad_watch, |_| Ok(())).is_err());
+        assert!(memory.data.borrow().iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn buffer_bounds() {
+        let memory = BufferMemory::new(BASE, vec![0; 8]);
+        let mut buf = [0; 4];
+        assert!(memory.read(BASE - 1, &mut buf).is_err());
+        assert!(memory.read(BASE + 5, &mut buf).is_err());
+        assert!(memory.write(BASE + 8, &[1]).is_err());
+        memory.write(BASE + 4, &[1, 2, 3, 4]).unwrap();
+        memory.read(BASE + 4, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn float_steps_are_inclusive() {
+        let steps: Vec<_> = float_steps(0.0, 1.0, 5).into_iter().map(f32::from_bits).collect();
+        assert_eq!(steps, vec![0.0, 0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(float_steps(2.0, 3.0, 1), vec![2.0f32.to_bits()]);
+    }
+}
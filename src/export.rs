@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::dlc_data::{DlcData, DlcInfo, DlcModelData, DlcPrsData};
+use crate::error::{Error, Result};
+use crate::level::LevelId;
+use crate::model::{DlcText, KartDlc, KartStats};
+use crate::music::SONG_NAME_LEN;
+use crate::set_file::{SetFile, SetObject};
+use crate::text::{self, Language, TextField, TEXT_LEN};
+use crate::vms::VmsHeader;
+
+pub const EXPORT_FILE: &'static str = "dlc.toml";
+
+// Text description of a DlcData. Blobs that don't read well as text live in
+// side files, named by path relative to the directory holding this.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
+pub enum DlcExport {
+    Kart {
+        header: String,
+        info: InfoExport,
+        kart: KartExport,
+    },
+    // The payload layout is unknown, so it is exported as the raw blob.
+    Type4 {
+        header: String,
+        payload: String,
+        info: InfoExport,
+    },
+    UnlockKey {
+        header: String,
+        key: u32,
+        data: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InfoExport {
+    pub dlc_type: u32,
+    pub level_ids: [LevelId; 8],
+    // Decoded with each language's codepage. Blank slots are left out.
+    pub texts: Vec<TextExport>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextExport {
+    pub language: Language,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub title: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub dlc_type: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub stage: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub character: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    // Hex of the whole buffer, keyed by field name, for fields whose string
+    // doesn't encode back to the same bytes (unmappable bytes, anything after
+    // the NUL). Used on import unless the string was edited.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub raw: BTreeMap<String, String>,
+}
+
+impl TextExport {
+    fn field(&self, field: TextField) -> &str {
+        match field {
+            TextField::Title => &self.title,
+            TextField::DlcType => &self.dlc_type,
+            TextField::Stage => &self.stage,
+            TextField::Character => &self.character,
+            TextField::Description => &self.description,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KartExport {
+    pub track_data: String,
+    pub kart_dlc: KartDlcExport,
+    pub set_file: SetFileExport,
+    pub model: ModelExport,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KartDlcExport {
+    pub autorun_slot_handicap_1: f32,
+    pub autorun_rank_handicap_1: f32,
+    pub autorun_not_first_handicap_1: f32,
+    pub autorun_slot_handicap_2: f32,
+    pub autorun_rank_handicap_2: f32,
+    pub autorun_not_first_handicap_2: f32,
+    // Raw, so values nobody has named yet survive a round trip.
+    pub ai_use_dlc_kart: u32,
+    pub song_name: String,
+    // Like TextExport::raw, for a song name that doesn't write back as-is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub song_name_raw: Option<String>,
+    pub stats: KartStats,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SetFileExport {
+    // Hex, kept as-is.
+    pub header: String,
+    pub objects: Vec<SetObject>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelExport {
+    pub model: String,
+    pub texlist: String,
+    pub texture: String,
+    // For reference only; import takes these from the blobs.
+    pub texture_count: u16,
+    pub texture_names: Vec<String>,
+}
+
+// Writes the side files into dir and returns the description referring to them.
+pub fn export_dlc<P>(dlc: &DlcData, dir: P) -> Result<DlcExport>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let mut header = Vec::new();
+    dlc.header().write_to(&mut header)?;
+    let header = write_blob(dir, "header.bin", &header)?;
+
+    let export = match *dlc {
+        DlcData::Kart { ref info, ref prs_data } => DlcExport::Kart {
+            header: header,
+            info: export_info(info),
+            kart: export_kart(prs_data, dir)?,
+        },
+        DlcData::Type4 { ref info, ref payload } => DlcExport::Type4 {
+            header: header,
+            payload: write_blob(dir, "payload.bin", payload)?,
+            info: export_info(info),
+        },
+        DlcData::UnlockKey { key, ref data, .. } => DlcExport::UnlockKey {
+            header: header,
+            key: key,
+            data: write_blob(dir, "data.bin", data)?,
+        },
+    };
+    Ok(export)
+}
+
+// Rebuilds a DlcData, reading side files relative to dir.
+pub fn import_dlc<P>(export: &DlcExport, dir: P) -> Result<DlcData>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    let dlc = match *export {
+        DlcExport::Kart { ref header, ref info, ref kart } => DlcData::Kart {
+            info: import_info(info, import_header(dir, header)?)?,
+            prs_data: import_kart(kart, dir)?,
+        },
+        DlcExport::Type4 { ref header, ref payload, ref info } => DlcData::Type4 {
+            info: import_info(info, import_header(dir, header)?)?,
+            payload: read_blob(dir, payload)?,
+        },
+        DlcExport::UnlockKey { ref header, key, ref data } => DlcData::UnlockKey {
+            header: import_header(dir, header)?,
+            key: key,
+            data: read_blob(dir, data)?,
+        },
+    };
+    Ok(dlc)
+}
+
+// Exports to dir, with the description in EXPORT_FILE.
+pub fn export_to_dir<P>(dlc: &DlcData, dir: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    let export = export_dlc(dlc, dir)?;
+    let text = toml::to_string(&export)
+        .map_err(|e| Error::Export(e.to_string()))?;
+    fs::write(dir.join(EXPORT_FILE), text)?;
+    Ok(())
+}
+
+pub fn import_from_dir<P>(dir: P) -> Result<DlcData>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    let path = dir.join(EXPORT_FILE);
+    let text = fs::read_to_string(&path)?;
+    let export = toml::from_str(&text)
+        .map_err(|e| Error::Export(format!("{}: {}", path.display(), e)))?;
+    import_dlc(&export, dir)
+}
+
+fn export_info(info: &DlcInfo) -> InfoExport {
+    let mut texts = Vec::new();
+    for &language in Language::ALL.iter() {
+        let text = &info.dlc_texts[language.index()];
+        let mut export = TextExport {
+            language: language,
+            title: text.get(TextField::Title, language),
+            dlc_type: text.get(TextField::DlcType, language),
+            stage: text.get(TextField::Stage, language),
+            character: text.get(TextField::Character, language),
+            description: text.get(TextField::Description, language),
+            raw: BTreeMap::new(),
+        };
+        for &field in TextField::ALL.iter() {
+            let raw = text.field(field);
+            let lossless = text::encode_text(export.field(field), language)
+                .map(|(encoded, _)| encoded[..] == raw[..])
+                .unwrap_or(false);
+            if !lossless {
+                export.raw.insert(field.name().to_string(), to_hex(raw));
+            }
+        }
+        if !export.raw.is_empty() || TextField::ALL.iter().any(|&field| !export.field(field).is_empty()) {
+            texts.push(export);
+        }
+    }
+    InfoExport {
+        dlc_type: info.dlc_type,
+        level_ids: info.level_ids,
+        texts: texts,
+    }
+}
+
+fn import_info(export: &InfoExport, header: VmsHeader) -> Result<DlcInfo> {
+    DlcInfo::check_level_ids(&export.level_ids, "info.level_ids")?;
+    let mut dlc_texts = [DlcText::default(); 6];
+    for text in export.texts.iter() {
+        let language = text.language;
+        if let Some(name) = text.raw.keys().find(|name| !TextField::ALL.iter().any(|field| field.name() == name.as_str())) {
+            return Err(Error::Export(format!("{}.raw: unknown field {:?}", language.name(), name)));
+        }
+        for &field in TextField::ALL.iter() {
+            let path = format!("{}.raw.{}", language.name(), field.name());
+            let raw = match text.raw.get(field.name()) {
+                Some(hex) => Some(raw_buffer(hex, TEXT_LEN, &path)?),
+                None => None,
+            };
+            let dlc_text = &mut dlc_texts[language.index()];
+            match raw {
+                Some(ref raw) if text::decode_text(raw, language) == text.field(field) => {
+                    dlc_text.field_mut(field).copy_from_slice(raw);
+                }
+                _ => if dlc_text.set(field, language, text.field(field))? {
+                    warn!("{}.{} is too long, truncated", language.name(), field.name());
+                },
+            }
+        }
+    }
+    Ok(DlcInfo {
+        header: header,
+        dlc_type: export.dlc_type,
+        dlc_texts: dlc_texts,
+        level_ids: export.level_ids,
+    })
+}
+
+fn export_kart(prs_data: &DlcPrsData, dir: &Path) -> Result<KartExport> {
+    let model_data = &prs_data.model_data;
+    let kart_dlc = &prs_data.kart_dlc;
+    Ok(KartExport {
+        track_data: write_blob(dir, "track.bin", &prs_data.track_data)?,
+        kart_dlc: KartDlcExport {
+            autorun_slot_handicap_1: kart_dlc.autorun_slot_handicap_1,
+            autorun_rank_handicap_1: kart_dlc.autorun_rank_handicap_1,
+            autorun_not_first_handicap_1: kart_dlc.autorun_not_first_handicap_1,
+            autorun_slot_handicap_2: kart_dlc.autorun_slot_handicap_2,
+            autorun_rank_handicap_2: kart_dlc.autorun_rank_handicap_2,
+            autorun_not_first_handicap_2: kart_dlc.autorun_not_first_handicap_2,
+            ai_use_dlc_kart: kart_dlc.ai_use_dlc_kart,
+            song_name: kart_dlc.song_name(),
+            song_name_raw: export_song_name_raw(kart_dlc),
+            stats: kart_dlc.stats,
+        },
+        set_file: SetFileExport {
+            header: to_hex(&prs_data.set_file.header),
+            objects: prs_data.set_file.objects.clone(),
+        },
+        model: ModelExport {
+            model: write_blob(dir, "model.bin", &model_data.raw_model()?)?,
+            texlist: write_blob(dir, "texlist.bin", &model_data.raw_texlist()?)?,
+            texture: write_blob(dir, "texture.bin", &model_data.raw_texture())?,
+            texture_count: model_data.texture_count(),
+            texture_names: model_data.texture_names()?,
+        },
+    })
+}
+
+fn import_kart(export: &KartExport, dir: &Path) -> Result<DlcPrsData> {
+    let kart = &export.kart_dlc;
+    let model = &export.model;
+    let model_data = DlcModelData::from_raw(
+        &read_blob(dir, &model.model)?,
+        &read_blob(dir, &model.texlist)?,
+        &read_blob(dir, &model.texture)?)?;
+
+    let mut kart_dlc = KartDlc {
+        stats: kart.stats,
+        autorun_slot_handicap_1: kart.autorun_slot_handicap_1,
+        autorun_rank_handicap_1: kart.autorun_rank_handicap_1,
+        autorun_not_first_handicap_1: kart.autorun_not_first_handicap_1,
+        autorun_slot_handicap_2: kart.autorun_slot_handicap_2,
+        autorun_rank_handicap_2: kart.autorun_rank_handicap_2,
+        autorun_not_first_handicap_2: kart.autorun_not_first_handicap_2,
+        ai_use_dlc_kart: kart.ai_use_dlc_kart,
+        song_name: [0; SONG_NAME_LEN],
+    };
+    if let Some(ref hex) = kart.song_name_raw {
+        kart_dlc.song_name.copy_from_slice(&raw_buffer(hex, SONG_NAME_LEN, "kart_dlc.song_name_raw")?);
+    }
+    // An edited name wins over the raw bytes.
+    if kart.song_name_raw.is_none() || kart_dlc.song_name() != kart.song_name {
+        kart_dlc.set_song_name(&kart.song_name)?;
+    }
+
+    Ok(DlcPrsData {
+        kart_dlc: kart_dlc,
+        set_file: SetFile {
+            header: from_hex(&export.set_file.header)?,
+            objects: export.set_file.objects.clone(),
+        },
+        track_data: read_blob(dir, &export.track_data)?,
+        model_data: model_data,
+    })
+}
+
+// None when song_name() writes back to the same bytes.
+fn export_song_name_raw(kart_dlc: &KartDlc) -> Option<String> {
+    let mut rewritten = *kart_dlc;
+    let lossless = rewritten.set_song_name(&kart_dlc.song_name()).is_ok()
+        && rewritten.song_name[..] == kart_dlc.song_name[..];
+    if lossless {
+        None
+    } else {
+        Some(to_hex(&kart_dlc.song_name))
+    }
+}
+
+fn raw_buffer(hex: &str, len: usize, path: &str) -> Result<Vec<u8>> {
+    let raw = from_hex(hex)?;
+    if raw.len() != len {
+        return Err(Error::Export(format!("{}: expected 0x{:x} bytes, got 0x{:x}", path, len, raw.len())));
+    }
+    Ok(raw)
+}
+
+fn import_header(dir: &Path, name: &str) -> Result<VmsHeader> {
+    VmsHeader::read_from(&read_blob(dir, name)?[..])
+}
+
+fn write_blob(dir: &Path, name: &str, data: &[u8]) -> Result<String> {
+    fs::write(dir.join(name), data)?;
+    Ok(name.to_string())
+}
+
+fn read_blob(dir: &Path, name: &str) -> Result<Vec<u8>> {
+    let path = dir.join(name);
+    fs::read(&path)
+        .map_err(|e| Error::Export(format!("{}: {}", path.display(), e)))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return Err(Error::Export(format!("bad hex {:?}", text)));
+    }
+    (0..text.len()).step_by(2)
+        .map(|idx| u8::from_str_radix(&text[idx..idx + 2], 16)
+            .map_err(|_| Error::Export(format!("bad hex {:?}", text))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use crate::dlc_data::tests::{sample_header, sample_info, sample_kart};
+    use crate::prs::Compression;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("sa2_dlc_export_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // A kart DLC with the kinds of bytes the string fields can't carry:
+    // Shift-JIS that doesn't decode, junk after a NUL and a song name with a
+    // path separator in it.
+    fn lossy_kart() -> DlcData {
+        let mut dlc = sample_kart();
+        {
+            let info = dlc.info_mut().unwrap();
+            info.dlc_texts[0].title[..4].copy_from_slice(&[0x83, 0x41, 0xfd, b'x']);
+            info.dlc_texts[0].description[..2].copy_from_slice(&[b'x', 0x83]);
+            info.dlc_texts[1].stage[..6].copy_from_slice(b"Kart\0!");
+        }
+        {
+            let kart_dlc = &mut dlc.kart_mut().unwrap().kart_dlc;
+            kart_dlc.song_name[..10].copy_from_slice(b"a/mine.adx");
+            kart_dlc.song_name[20] = b'z';
+        }
+        dlc
+    }
+
+    fn fixture() -> Vec<u8> {
+        let mut file = Vec::new();
+        lossy_kart().to_vmu(&mut file, Compression::Fast).unwrap();
+        file
+    }
+
+    fn through_export(file: &[u8], name: &str) -> Vec<u8> {
+        let dir = test_dir(name);
+        let dlc = DlcData::from_vmu(Cursor::new(file)).unwrap();
+        export_to_dir(&dlc, &dir).unwrap();
+        let imported = import_from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut out = Vec::new();
+        imported.to_vmu(&mut out, Compression::Fast).unwrap();
+        out
+    }
+
+    #[test]
+    fn kart_round_trip_is_byte_exact() {
+        let file = fixture();
+        assert!(through_export(&file, "kart") == file);
+    }
+
+    #[test]
+    fn other_kinds_round_trip() {
+        let dlc_vec = vec![
+            DlcData::Type4 {
+                info: sample_info(DlcData::TYPE4_TYPE),
+                payload: vec![1, 2, 3, 4, 5],
+            },
+            DlcData::UnlockKey {
+                header: sample_header(),
+                key: 1234567,
+                data: vec![9; 20],
+            },
+        ];
+        for (idx, dlc) in dlc_vec.iter().enumerate() {
+            let mut file = Vec::new();
+            dlc.to_vmu(&mut file, Compression::Fast).unwrap();
+            assert!(through_export(&file, &format!("other{}", idx)) == file);
+        }
+    }
+
+    #[test]
+    fn raw_only_where_lossy() {
+        let dlc = lossy_kart();
+        let dir = test_dir("raw");
+        let export = export_dlc(&dlc, &dir).unwrap();
+        let imported = import_dlc(&export, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(imported == dlc);
+        assert!(imported.kart().unwrap().kart_dlc.song_name[..] == dlc.kart().unwrap().kart_dlc.song_name[..]);
+        let (info, kart) = match export {
+            DlcExport::Kart { info, kart, .. } => (info, kart),
+            _ => panic!("expected a kart export"),
+        };
+        let raw: Vec<_> = info.texts.iter()
+            .flat_map(|text| text.raw.keys().map(move |field| format!("{}.{}", text.language.name(), field)))
+            .collect();
+        assert_eq!(raw, vec!["japanese.description", "japanese.title", "english.stage"]);
+        assert!(kart.kart_dlc.song_name_raw.is_some());
+
+        let mut clean = sample_kart();
+        clean.kart_mut().unwrap().kart_dlc.set_song_name("a_mine.adx").unwrap();
+        let dir = test_dir("clean");
+        match export_dlc(&clean, &dir).unwrap() {
+            DlcExport::Kart { info, kart, .. } => {
+                assert!(info.texts.iter().all(|text| text.raw.is_empty()));
+                assert!(kart.kart_dlc.song_name_raw.is_none());
+            }
+            _ => panic!("expected a kart export"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edits_win_over_raw() {
+        let dlc = lossy_kart();
+        let dir = test_dir("edit");
+        let mut export = export_dlc(&dlc, &dir).unwrap();
+        if let DlcExport::Kart { ref mut info, ref mut kart, .. } = export {
+            info.texts[1].stage = "Track".to_string();
+            kart.kart_dlc.song_name = "a_mine.adx".to_string();
+        }
+        let imported = import_dlc(&export, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let info = imported.info().unwrap();
+        assert_eq!(info.dlc_texts[1].get(TextField::Stage, Language::English), "Track");
+        assert_eq!(info.dlc_texts[1].stage[5], 0);
+        // Untouched fields still come back byte for byte.
+        assert!(info.dlc_texts[0].title[..] == dlc.info().unwrap().dlc_texts[0].title[..]);
+        let kart_dlc = &imported.kart().unwrap().kart_dlc;
+        assert_eq!(kart_dlc.song_name(), "a_mine.adx");
+        assert_eq!(kart_dlc.song_name[20], 0);
+    }
+}
@@ -0,0 +1,414 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use log::{debug, error, info, warn};
+
+use crate::config::{Config, CONFIG_FILE};
+use crate::dlc_data::{self, DlcData};
+use crate::error::Result;
+use crate::logger;
+use crate::model::*;
+use crate::music::MusicConfig;
+use crate::process_reader::ProcessHandle;
+use crate::{loader, tuning, validate};
+
+const DLC_PREFIX: &'static str = "resource/gd_PC/SAVEDATA/DLC/";
+const EVENT_TABLE: u32 = 0x01d1c660;
+static mut dlc: Option<DlcData> = None;
+static mut DLC_ARRAY: Option<Vec<DlcData>> = None;
+static mut CONFIG: Option<Config> = None;
+// Stand-in for the game's event table once it is too small.
+static mut RELOCATED_TABLE: Option<Vec<DlcDescriptor>> = None;
+// Little endian copies of the set files, one per DLC_ARRAY entry. The game
+// reads the selected one after the kart hook returns.
+static mut SET_DATA: Option<Vec<Vec<u8>>> = None;
+
+pub trait ProcessHandleExt {
+    fn write_jump(&self, address: u32, function: *const fn()) -> Result<()>;
+    fn write_call(&self, address: u32, function: *const fn()) -> Result<()>;
+    fn write_rel32(&self, address: u32, opcode: u8, function: *const fn()) -> Result<()>;
+}
+
+impl ProcessHandleExt for ProcessHandle {
+    fn write_jump(&self, address: u32, function: *const fn()) -> Result<()> {
+        self.write_rel32(address, 0xe9, function)
+    }
+
+    fn write_call(&self, address: u32, function: *const fn()) -> Result<()> {
+        self.write_rel32(address, 0xe8, function)
+    }
+
+    fn write_rel32(&self, address: u32, opcode: u8, function: *const fn()) -> Result<()> {
+        let function_address = function as u32;
+        let rel = function_address.wrapping_sub(address + 5);
+        let patch = [opcode, rel as u8, (rel >> 8) as u8, (rel >> 16) as u8, (rel >> 24) as u8];
+        self.write_data(address, &patch)?;
+        self.verify_data(address, &patch)
+    }
+}
+
+#[repr(C)]
+pub struct ModInfo {
+    version: u32,
+    init: u32,
+    padding: [u32; 8],
+}
+
+#[no_mangle]
+pub static SA2ModInfo: ModInfo = ModInfo {
+    version: 1,
+    init: 0,
+    padding: [0; 8],
+};
+
+#[naked]
+unsafe fn kart_dlc_load_some_prs_thing_hook() {
+    asm!("
+        pushal
+        push %eax
+        push %ebx
+        mov $0, %ecx
+        call *%ecx
+        add $$0x08, %esp
+        popal
+        push %ecx
+        push %esi
+        push %edi
+        push 0x01defe20
+        mov $1, %ecx
+        jmp *%ecx
+    "
+    :
+    : "i" (print_registers as *const fn() as u32), "i" (0x00799aa8)
+    );
+}
+
+#[naked]
+unsafe fn wrap_init_events() {
+    asm!("
+        pushal
+        mov $0, %eax
+        call *%eax
+        popal
+        ret
+    "
+    :
+    : "i" (init_events as *const fn() as u32)
+    );
+}
+
+fn init_texlist_with_textures(texlist: u32, texture: u32) {
+    unsafe {
+        asm!("
+            mov $0, %eax
+            mov $1, %ebx
+            mov $2, %ecx
+            call *%ecx
+        "
+        :
+        : "r" (texture), "r" (texlist), "i" (0x0042fc30)
+        : "eax", "ebx", "ecx"
+        );
+    }
+}
+
+extern "C" fn kart_initialize_data_pre_hook() {
+    unsafe {
+        if let (Some(dlc_vec), Some(set_vec)) = (DLC_ARRAY.as_ref(), SET_DATA.as_ref()) {
+            let selection = *(0x01d1b848 as *mut u32);
+            if let Some(prs_data) = dlc_vec.get(selection as usize).and_then(DlcData::kart) {
+                *(0x01d97100 as *mut *const KartDlc) = &prs_data.kart_dlc;
+                let set_data = &set_vec[selection as usize];
+                let set_ptr = set_data.as_ptr() as u32;
+                *(0x01d97104 as *mut u32) = set_ptr;
+                *(0x01d97108 as *mut u32) = set_data.len() as u32;
+                let track_ptr = prs_data.track_data.as_ptr() as u32;
+                *(0x01d9710c as *mut u32) = track_ptr;
+                *(0x01d97110 as *mut u32) = prs_data.track_data.len() as u32;
+                *(0x01d97054 as *mut u32) = prs_data.model_data.model_ptr;
+            }
+        }
+        let func_ptr = 0x0061a3b0;
+        (*(&func_ptr as *const i32 as *const extern "C" fn()))();
+    }
+}
+
+extern "C" fn print_registers(ebx: u32, eax: u32) {
+    debug!("eax: 0x{:08x}", eax);
+    debug!("ebx: 0x{:08x}", ebx);
+    let handle = ProcessHandle::open_current_process();
+    match handle.read_copy::<u32>(eax) {
+        Ok(v) => debug!("*eax: 0x{:08x}", v),
+        Err(e) => debug!("*eax: {}", e),
+    }
+    match handle.read_copy::<u32>(ebx) {
+        Ok(v) => debug!("*ebx: 0x{:08x}", v),
+        Err(e) => debug!("*ebx: {}", e),
+    }
+
+    unsafe {
+        if let Some(ref dlc_vec) = DLC_ARRAY {
+            let selection = *(0x01d1b848 as *mut u32);
+            if let Some(prs_data) = dlc_vec.get(selection as usize).and_then(DlcData::kart) {
+                let texlist_addr = prs_data.model_data.texlist_ptr;
+                let texture_addr = prs_data.model_data.texture.as_ptr() as u32;
+                init_texlist_with_textures(texlist_addr, texture_addr);
+                *(0x01d9705c as *mut u32) = texlist_addr;
+            }
+        }
+    }
+}
+
+extern "C" fn init_events() {
+    let num_events = match read_event_info() {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Could not load DLC events: {}", e);
+            0
+        },
+    };
+    unsafe {
+        *(0x01a50220 as *mut u32) = 0;
+        *(0x01a50224 as *mut u32) = 0;
+        *(0x01d1b848 as *mut u32) = 0;
+        *(0x01d1b84c as *mut u32) = num_events;
+        *(0x01a501d8 as *mut u32) = event_table_address();
+    }
+}
+
+// The game reaches the table through 0x01a501d8, so once there are more
+// events than it has room for, copy its entries to a table of our own and
+// hand it that instead. Either way each entry starts out as the game's.
+unsafe fn write_event_table(dlc_vec: &[DlcData]) {
+    let game_table = std::slice::from_raw_parts_mut(EVENT_TABLE as *mut DlcDescriptor, DlcDescriptor::TABLE_CAPACITY);
+    RELOCATED_TABLE = dlc_data::write_event_table(game_table, dlc_vec);
+    if let Some(ref table) = RELOCATED_TABLE {
+        warn!("{} events exceed the game's table of {}, relocated it",
+            table.len(), DlcDescriptor::TABLE_CAPACITY);
+    }
+}
+
+fn event_table_address() -> u32 {
+    unsafe {
+        match RELOCATED_TABLE {
+            Some(ref table) => table.as_ptr() as u32,
+            None => EVENT_TABLE,
+        }
+    }
+}
+
+fn read_event_info() -> Result<u32> {
+    let loader_config = unsafe { CONFIG.as_ref() }
+        .map(|config| config.loader.clone())
+        .unwrap_or_default();
+    let (mut dlc_vec, report) = loader::load_dlc_dir(DLC_PREFIX, &loader_config)?;
+    report.log();
+    apply_tuning(&mut dlc_vec);
+    check_music(&mut dlc_vec);
+    // After tuning, since a profile can push values out of range too.
+    let dlc_vec = validate_karts(dlc_vec);
+    // Event indices have to line up with DLC_ARRAY, so anything without an
+    // event is dropped here. Type 0x04 payloads aren't understood, so there
+    // is nothing to hand the game for them.
+    let dlc_vec: Vec<_> = dlc_vec.into_iter()
+        .filter(|dlc_read| match *dlc_read {
+            DlcData::Kart { .. } | DlcData::UnlockKey { .. } => true,
+            DlcData::Type4 { ref info, .. } => {
+                warn!("Not registering {}: type 0x04 DLC are recognised, but their payload isn't understood",
+                    info.header.vmu_description_str());
+                false
+            }
+        })
+        .collect();
+    // Built once here rather than every time a race starts.
+    let set_vec = dlc_vec.iter()
+        .map(|dlc_read| match dlc_read.kart() {
+            Some(prs_data) => prs_data.set_file.to_le_bytes(),
+            None => Ok(Vec::new()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    unsafe {
+        write_event_table(&dlc_vec);
+    }
+    let ret = dlc_vec.len();
+    unsafe {
+        SET_DATA = Some(set_vec);
+        DLC_ARRAY = Some(dlc_vec);
+    }
+    Ok(ret as u32)
+}
+
+fn apply_tuning(dlc_vec: &mut [DlcData]) {
+    let config = match unsafe { CONFIG.as_ref() } {
+        Some(config) => config,
+        None => return,
+    };
+    let adjustments = match config.tuning.active() {
+        Ok(Some(adjustments)) => {
+            if let Some(ref profile) = config.tuning.profile {
+                info!("Applying tuning profile {:?}", profile);
+            }
+            adjustments
+        }
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            error!("Not tuning karts: {}", e);
+            Vec::new()
+        }
+    };
+    for prs_data in dlc_vec.iter_mut().filter_map(DlcData::kart_mut) {
+        tuning::apply_adjustments(&adjustments, &mut prs_data.kart_dlc);
+        config.tuning.ai_kart.apply(&mut prs_data.kart_dlc);
+    }
+}
+
+fn check_music(dlc_vec: &mut [DlcData]) {
+    let default_music = MusicConfig::default();
+    let music = unsafe { CONFIG.as_ref() }
+        .map(|config| &config.music)
+        .unwrap_or(&default_music);
+    for prs_data in dlc_vec.iter_mut().filter_map(DlcData::kart_mut) {
+        let song = prs_data.kart_dlc.song_name();
+        if music.song_exists(&song) {
+            continue;
+        }
+        if !music.song_exists(&music.fallback_song) {
+            error!("Song {:?} not found in {}, and neither is the fallback {:?}", song, music.adx_dir, music.fallback_song);
+            continue;
+        }
+        warn!("Song {:?} not found in {}, using {:?}", song, music.adx_dir, music.fallback_song);
+        if let Err(e) = prs_data.kart_dlc.set_song_name(&music.fallback_song) {
+            error!("Could not use fallback song: {}", e);
+        }
+    }
+}
+
+fn validate_karts(dlc_vec: Vec<DlcData>) -> Vec<DlcData> {
+    let mode = unsafe { CONFIG.as_ref() }
+        .map(|config| config.validation.mode)
+        .unwrap_or_default();
+    dlc_vec.into_iter()
+        .filter_map(|mut dlc_read| {
+            let name = dlc_read.header().vmu_description_str();
+            if let Some(prs_data) = dlc_read.kart_mut() {
+                match validate::validate_kart(&mut prs_data.kart_dlc, mode) {
+                    Ok(issues) => {
+                        for issue in issues {
+                            warn!("{}: {}", name, issue);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Skipped {}: {}", name, e);
+                        return None;
+                    }
+                }
+            }
+            Some(dlc_read)
+        })
+        .collect()
+}
+
+fn apply_patches(handle: &ProcessHandle) -> Result<()> {
+    handle.write_jump(0x00799aa0, kart_dlc_load_some_prs_thing_hook as *const fn())?;
+
+    handle.write_call(0x0068c7c7, wrap_init_events as *const fn())?;
+    handle.write_copy(0x00666f90, 0xc3u8)?;
+
+    handle.write_copy(0x00665547, 0xb)?;
+
+    handle.write_copy(0x0068c2da, 0x50)?;
+    handle.write_copy(0x0068c2e1, 0x50)?;
+    handle.write_copy(0x0068ab2a, 0x50)?;
+
+    handle.write_copy(0x0100acfc, kart_initialize_data_pre_hook as *const fn())?;
+
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn Init(path: *const c_char, _helper_functions: u32) {
+    let mod_path = if path.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned()
+    };
+    let (config, config_err) = match Config::load(Path::new(&mod_path).join(CONFIG_FILE)) {
+        Ok(config) => (config, None),
+        Err(e) => (Config::default(), Some(e)),
+    };
+    if let Err(e) = logger::init(&config.log) {
+        println!("Could not start logging: {}", e);
+    }
+    if let Some(e) = config_err {
+        error!("Using default config: {}", e);
+    }
+    info!("Loaded from {:?}", mod_path);
+    unsafe {
+        CONFIG = Some(config);
+    }
+
+    let handle = ProcessHandle::open_current_process();
+    if let Err(e) = apply_patches(&handle) {
+        error!("Could not patch game: {}", e);
+    }
+
+//    let file = File::open("resource/gd_PC/SAVEDATA/KartFZ.VMS").unwrap();
+//    let dlc_read = DlcData::from_vmu(file).unwrap();
+//
+//    unsafe {
+//        dlc = Some(dlc_read);
+//
+//        if let Some(ref mut dlc_read) = dlc {
+//            *(0x01d1c678 as *mut u32) = dlc_read.dlc_type;
+//            *(0x01d1c69c as *mut [DlcText; 6]) = dlc_read.dlc_texts;
+//            *(0x01d1c67c as *mut [u32; 8]) = dlc_read.level_ids;
+//
+//            *(0x01d97100 as *mut *const KartDlc) = &dlc_read.prs_data.kart_dlc;
+//            let set_ptr = dlc_read.prs_data.set_data.as_ptr() as u32;
+//            *(0x01d97104 as *mut u32) = set_ptr;
+//            *(0x01d97108 as *mut u32) = dlc_read.prs_data.set_data.len() as u32;
+//            let track_ptr = dlc_read.prs_data.track_data.as_ptr() as u32;
+//            *(0x01d9710c as *mut u32) = track_ptr;
+//            *(0x01d97110 as *mut u32) = dlc_read.prs_data.track_data.len() as u32;
+//            *(0x01d97054 as *mut u32) = dlc_read.prs_data.model_data.model_ptr;
+//        }
+//    }
+
+//    unsafe {
+//        *((0x01d1c67c) as *mut u32) = 0x46;
+//    }
+
+//    handle.write_copy(0x01d1c678, 0x3).unwrap();
+//    handle.write_copy(0x01d1c664, 0x1).unwrap();
+
+//    handle.write_data(0x01d1c91c, b"\tDLC name\x00").unwrap();
+//    handle.write_data(0x01d1c99c, b"DLC type\x00").unwrap();
+//    handle.write_data(0x01d1ca1c, b"DLC stage\x00").unwrap();
+//    handle.write_data(0x01d1ca9c, b"DLC character\x00").unwrap();
+//    handle.write_data(0x01d1cb1c, b"DLC description\x00").unwrap();
+//
+//    handle.write_data(0x01d1d858, b"\tDLC name2\x00").unwrap();
+//
+//    handle.write_copy(0x01d97100, 0x1d97070).unwrap();
+//    handle.write_data(0x01d970b4, b"a_mine.adx\x00").unwrap();
+//
+//    let mut file = File::open("resource/gd_PC/setCartMini1.bin").unwrap();
+//
+//    unsafe {
+//        let mut set_buf = Vec::new();
+//        file.read_to_end(&mut set_buf).unwrap();
+//        set_buf.swap(0, 3);
+//        set_buf.swap(1, 2);
+//        let set_ptr = set_buf.as_ptr() as u32;
+//        *(0x01d97104 as *mut u32) = set_ptr;
+//        *(0x01d97108 as *mut u32) = set_buf.len() as u32;
+//        setfile = Some(set_buf);
+//
+//        let track_buf: Vec<u8> = iter::repeat(3).take(100).collect();
+//        let track_ptr = track_buf.as_ptr() as u32;
+//        *(0x01d9710c as *mut u32) = track_ptr;
+//        *(0x01d97110 as *mut u32) = track_buf.len() as u32;
+//        trackdata = Some(track_buf);
+//    }
+}
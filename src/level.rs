@@ -0,0 +1,122 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// Stage number as the game uses it. Transparent so it can sit in structs
+// that are handed to the game.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LevelId(pub u32);
+
+const LEVEL_NAMES: [&'static str; 68] = [
+    "Basic Test",
+    "Knuckles Test",
+    "Sonic Test",
+    "Green Forest",
+    "White Jungle",
+    "Pumpkin Hill",
+    "Sky Rail",
+    "Aquatic Mine",
+    "Security Hall",
+    "Prison Lane",
+    "Metal Harbor",
+    "Iron Gate",
+    "Weapons Bed",
+    "City Escape",
+    "Radical Highway",
+    "Weapons Bed 2P",
+    "Wild Canyon",
+    "Mission Street",
+    "Dry Lagoon",
+    "Sonic vs. Shadow 1",
+    "Tails vs. Eggman 1",
+    "Sand Ocean",
+    "Crazy Gadget",
+    "Hidden Base",
+    "Eternal Engine",
+    "Death Chamber",
+    "Egg Quarters",
+    "Lost Colony",
+    "Pyramid Cave",
+    "Tails vs. Eggman 2",
+    "Final Rush",
+    "Green Hill",
+    "Meteor Herd",
+    "Knuckles vs. Rouge",
+    "Cannon's Core (Sonic)",
+    "Cannon's Core (Eggman)",
+    "Cannon's Core (Tails)",
+    "Cannon's Core (Rouge)",
+    "Cannon's Core (Knuckles)",
+    "Mission Street 2P",
+    "Final Chase",
+    "Wild Canyon 2P",
+    "Sonic vs. Shadow 2",
+    "Cosmic Wall",
+    "Mad Space",
+    "Sand Ocean 2P",
+    "Dry Lagoon 2P",
+    "Pyramid Race",
+    "Hidden Base 2P",
+    "Pool Quest",
+    "Planet Quest",
+    "Deck Race",
+    "Downtown Race",
+    "Cosmic Wall 2P",
+    "Grind Race",
+    "Lost Colony 2P",
+    "Eternal Engine 2P",
+    "Metal Harbor 2P",
+    "Iron Gate 2P",
+    "Death Chamber 2P",
+    "Big Foot",
+    "Hot Shot",
+    "Flying Dog",
+    "King Boom Boo",
+    "Egg Golem (Sonic)",
+    "Biolizard",
+    "Final Hazard",
+    "Egg Golem (Eggman)",
+];
+
+impl LevelId {
+    pub const BASIC_TEST: LevelId = LevelId(0);
+    pub const CITY_ESCAPE: LevelId = LevelId(13);
+    pub const GREEN_HILL: LevelId = LevelId(31);
+    pub const FINAL_HAZARD: LevelId = LevelId(66);
+    // The stage kart DLC point at.
+    pub const ROUTE_101_280: LevelId = LevelId(70);
+    pub const KART_RACE: LevelId = LevelId(71);
+    pub const CHAO_WORLD: LevelId = LevelId(90);
+
+    pub fn from_u32(id: u32) -> Option<LevelId> {
+        let level = LevelId(id);
+        if level.is_valid() {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            LevelId::ROUTE_101_280 => Some("Route 101/280"),
+            LevelId::KART_RACE => Some("Kart Race"),
+            LevelId::CHAO_WORLD => Some("Chao World"),
+            LevelId(id) => LEVEL_NAMES.get(id as usize).cloned(),
+        }
+    }
+
+    pub fn is_valid(self) -> bool {
+        self.name().is_some()
+    }
+}
+
+impl fmt::Display for LevelId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{} ({})", self.0, name),
+            None => write!(f, "{} (unknown)", self.0),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::config;
+use crate::dlc_data::DlcData;
+use crate::error::{Error, Result};
+use crate::sidecar::Sidecar;
+
+// Optional, lives in the DLC directory next to the VMS files. Files ending in
+// .toml are never loaded as DLC, which covers this and the sidecars.
+pub const MANIFEST_FILE: &'static str = "load_order.toml";
+
+// In config.toml, under [loader].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LoaderConfig {
+    // Skip files whose VMS CRC doesn't match instead of loading them anyway.
+    pub verify_crc: bool,
+}
+
+// `load` gives the exact files and order to register; without it every file
+// in the directory is loaded, sorted by name. `disabled` applies either way.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    pub load: Option<Vec<String>>,
+    pub disabled: Vec<String>,
+}
+
+impl Manifest {
+    // A missing manifest means every file, in name order.
+    pub fn load<P>(path: P) -> Result<Manifest>
+    where
+        P: AsRef<Path>,
+    {
+        config::load_toml_or_default(path)
+    }
+
+    // File names compare case-insensitively, like the Windows filesystem.
+    pub fn is_disabled(&self, path: &Path) -> bool {
+        let name = file_name(path);
+        self.disabled.iter().any(|disabled| disabled.eq_ignore_ascii_case(&name))
+    }
+}
+
+pub struct LoadFailure {
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+#[derive(Default)]
+pub struct LoadReport {
+    pub loaded: Vec<PathBuf>,
+    pub disabled: Vec<PathBuf>,
+    pub failed: Vec<LoadFailure>,
+}
+
+impl LoadReport {
+    pub fn log(&self) {
+        for path in self.loaded.iter() {
+            info!("Loaded {}", path.display());
+        }
+        for path in self.disabled.iter() {
+            info!("Disabled {}", path.display());
+        }
+        for failure in self.failed.iter() {
+            warn!("Skipped {}: {}", failure.path.display(), failure.error);
+        }
+        info!("Loaded {} DLC file(s), disabled {}, skipped {}",
+            self.loaded.len(), self.disabled.len(), self.failed.len());
+    }
+}
+
+// Loads the DLC files in dir in a stable order, so event indices match
+// between machines. A file that fails to load is recorded in the report and
+// skipped, and so is a broken manifest, which leaves name order and nothing
+// disabled. Only failing to read the directory is an error.
+pub fn load_dlc_dir<P>(dir: P, config: &LoaderConfig) -> Result<(Vec<DlcData>, LoadReport)>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    let mut report = LoadReport::default();
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let manifest = match Manifest::load(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            report.failed.push(LoadFailure {
+                path: manifest_path,
+                error: e,
+            });
+            Manifest::default()
+        }
+    };
+    let paths = match manifest.load {
+        Some(ref names) => names.iter().map(|name| dir.join(name)).collect(),
+        None => scan_dir(dir, &mut report)?,
+    };
+
+    let mut dlc_vec = Vec::new();
+    for path in paths {
+        if manifest.is_disabled(&path) {
+            report.disabled.push(path);
+            continue;
+        }
+        match load_dlc_file(&path, config) {
+            Ok(dlc_read) => {
+                dlc_vec.push(dlc_read);
+                report.loaded.push(path);
+            }
+            Err(e) => report.failed.push(LoadFailure {
+                path: path,
+                error: e,
+            }),
+        }
+    }
+    Ok((dlc_vec, report))
+}
+
+pub fn load_dlc_file<P>(path: P, config: &LoaderConfig) -> Result<DlcData>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let mut dlc = if config.verify_crc {
+        DlcData::from_vmu_strict(file)?
+    } else {
+        DlcData::from_vmu(BufReader::new(file))?
+    };
+    Sidecar::load(Sidecar::path_for(path))?.apply(&mut dlc)?;
+    Ok(dlc)
+}
+
+// read_dir order is up to the OS, so sort by name. Case only breaks ties.
+fn scan_dir(dir: &Path, report: &mut LoadReport) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry_res in fs::read_dir(dir)? {
+        let path = match entry_res {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                report.failed.push(LoadFailure {
+                    path: dir.to_path_buf(),
+                    error: e.into(),
+                });
+                continue;
+            }
+        };
+        if path.is_dir() || is_toml(&path) {
+            continue;
+        }
+        paths.push(path);
+    }
+    paths.sort_by_key(|path| {
+        let name = file_name(path);
+        (name.to_ascii_lowercase(), name)
+    });
+    Ok(paths)
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().eq_ignore_ascii_case("toml"))
+        .unwrap_or(false)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    // None of these are valid VMS files, so every file that isn't disabled
+    // lands in report.failed, in the order it was tried.
+    fn test_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("sa2_dlc_loader_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for &(name, contents) in files.iter() {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths.iter().map(|path| file_name(path)).collect()
+    }
+
+    fn tried(report: &LoadReport) -> Vec<String> {
+        let paths: Vec<_> = report.failed.iter().map(|failure| failure.path.clone()).collect();
+        names(&paths)
+    }
+
+    #[test]
+    fn no_manifest_sorts_by_name() {
+        let dir = test_dir("sorted", &[("b.vms", ""), ("A.vms", ""), ("a.vms", ""), ("c.toml", "")]);
+        fs::create_dir(dir.join("sub")).unwrap();
+        let (dlc_vec, report) = load_dlc_dir(&dir, &LoaderConfig::default()).unwrap();
+        assert!(dlc_vec.is_empty());
+        assert_eq!(tried(&report), vec!["A.vms", "a.vms", "b.vms"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_order_and_disabled() {
+        let manifest = "load = [\"c.vms\", \"a.vms\", \"b.vms\"]\ndisabled = [\"A.VMS\"]\n";
+        let dir = test_dir("manifest", &[("a.vms", ""), ("b.vms", ""), ("c.vms", ""), (MANIFEST_FILE, manifest)]);
+        let (_, report) = load_dlc_dir(&dir, &LoaderConfig::default()).unwrap();
+        assert_eq!(tried(&report), vec!["c.vms", "b.vms"]);
+        assert_eq!(names(&report.disabled), vec!["a.vms"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disabled_without_load_list() {
+        let dir = test_dir("disabled", &[("a.vms", ""), ("b.vms", ""), (MANIFEST_FILE, "disabled = [\"a.vms\"]")]);
+        let (_, report) = load_dlc_dir(&dir, &LoaderConfig::default()).unwrap();
+        assert_eq!(tried(&report), vec!["b.vms"]);
+        assert_eq!(names(&report.disabled), vec!["a.vms"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_manifest_falls_back() {
+        let dir = test_dir("broken", &[("b.vms", ""), ("a.vms", ""), (MANIFEST_FILE, "load = 3\ndisabled = [\"a.vms\"]")]);
+        let (_, report) = load_dlc_dir(&dir, &LoaderConfig::default()).unwrap();
+        assert_eq!(tried(&report), vec![MANIFEST_FILE, "a.vms", "b.vms"]);
+        assert!(report.disabled.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_dir() {
+        let dir = env::temp_dir().join(format!("sa2_dlc_loader_missing_{}", std::process::id()));
+        assert!(load_dlc_dir(&dir, &LoaderConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use log::{self, LevelFilter, Log, Metadata, Record};
+
+use crate::config::LogConfig;
+
+struct Logger {
+    level: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+    file: Option<Mutex<File>>,
+    stdout: bool,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        // Module targets look like `sa2_button_mod::dlc_data`, but the config
+        // can leave off the crate name.
+        let module = target.splitn(2, "::").nth(1).unwrap_or(target);
+        self.targets.iter()
+            .filter(|&&(ref prefix, _)| matches_module(target, prefix) || matches_module(module, prefix))
+            .max_by_key(|&&(ref prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(self.level)
+    }
+}
+
+fn matches_module(target: &str, prefix: &str) -> bool {
+    target == prefix || (target.starts_with(prefix) && target[prefix.len()..].starts_with("::"))
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("[{:<5} {}] {}\n", record.level(), record.target(), record.args());
+        if let Some(ref file) = self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.write_all(line.as_bytes());
+            }
+        }
+        if self.stdout {
+            print!("{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(ref file) = self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let file = match config.file {
+        Some(ref path) => Some(Mutex::new(File::create(path)?)),
+        None => None,
+    };
+    let targets: Vec<_> = config.targets.iter()
+        .map(|(target, &level)| (target.clone(), level))
+        .collect();
+    let max_level = targets.iter()
+        .map(|&(_, level)| level)
+        .fold(config.level, |max, level| max.max(level));
+
+    let logger = Logger {
+        level: config.level,
+        targets: targets,
+        file: file,
+        stdout: config.stdout,
+    };
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
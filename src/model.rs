@@ -1,4 +1,14 @@
-#[derive(Clone, Copy, Debug, Default)]
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+
+use crate::level::LevelId;
+
+// The unknown fields here and in DlcDescriptor can be probed with
+// experiment::run_sweep. They keep their placeholder names until a sweep
+// against the game shows what they do.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct KartStats {
     pub accel: f32,
     pub brake_force: f32,
@@ -12,6 +22,34 @@ pub struct KartStats {
     pub hard_speed_cap: f32,
 }
 
+// Whether AI racers drive the DLC kart. Only 0 and 1 have been seen; other
+// values are kept so they can be written back untouched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AiKart {
+    OwnKart,
+    DlcKart,
+    Other(u32),
+}
+
+impl AiKart {
+    pub fn from_u32(value: u32) -> AiKart {
+        match value {
+            0 => AiKart::OwnKart,
+            1 => AiKart::DlcKart,
+            _ => AiKart::Other(value),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            AiKart::OwnKart => 0,
+            AiKart::DlcKart => 1,
+            AiKart::Other(value) => value,
+        }
+    }
+}
+
+#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct KartDlc {
     pub stats: KartStats,
@@ -21,12 +59,38 @@ pub struct KartDlc {
     pub autorun_slot_handicap_2: f32,
     pub autorun_rank_handicap_2: f32,
     pub autorun_not_first_handicap_2: f32,
+    // Raw, since the game reads this struct as is; see ai_kart.
     pub ai_use_dlc_kart: u32,
     // char is actually a u32 in rust
     // this is because it actually represents unicode well
     pub song_name: [u8; 64],
 }
 
+impl PartialEq for KartDlc {
+    fn eq(&self, other: &KartDlc) -> bool {
+        self.stats == other.stats
+            && self.autorun_slot_handicap_1 == other.autorun_slot_handicap_1
+            && self.autorun_rank_handicap_1 == other.autorun_rank_handicap_1
+            && self.autorun_not_first_handicap_1 == other.autorun_not_first_handicap_1
+            && self.autorun_slot_handicap_2 == other.autorun_slot_handicap_2
+            && self.autorun_rank_handicap_2 == other.autorun_rank_handicap_2
+            && self.autorun_not_first_handicap_2 == other.autorun_not_first_handicap_2
+            && self.ai_use_dlc_kart == other.ai_use_dlc_kart
+            && self.song_name[..] == other.song_name[..]
+    }
+}
+
+impl KartDlc {
+    pub fn ai_kart(&self) -> AiKart {
+        AiKart::from_u32(self.ai_use_dlc_kart)
+    }
+
+    pub fn set_ai_kart(&mut self, ai_kart: AiKart) {
+        self.ai_use_dlc_kart = ai_kart.to_u32();
+    }
+}
+
+#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct DlcText {
     pub title: [u8; 128],
@@ -48,7 +112,19 @@ impl Default for DlcText {
     }
 }
 
-#[derive(Clone, Copy)]
+impl PartialEq for DlcText {
+    fn eq(&self, other: &DlcText) -> bool {
+        self.title[..] == other.title[..]
+            && self.dlc_type[..] == other.dlc_type[..]
+            && self.stage[..] == other.stage[..]
+            && self.character[..] == other.character[..]
+            && self.description[..] == other.description[..]
+    }
+}
+
+// One entry of the game's event table.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
 pub struct DlcDescriptor {
     pub unknown1: u32,
     pub event_id: u32,
@@ -57,6 +133,79 @@ pub struct DlcDescriptor {
     pub unknown4: u32,
     pub unknown5: u32,
     pub dlc_type: u32,
-    pub levels: [u32; 8],
+    pub levels: [LevelId; 8],
     pub text: [DlcText; 6],
 }
+
+impl DlcDescriptor {
+    pub const SIZE: usize = 0xf3c;
+    // Entries assumed to fit at 0x01d1c660, the block the event table pointer
+    // at 0x01a501d8 is aimed at. The size of that block hasn't been found in
+    // the game code, so this is a deliberately small guess (0x79e0 bytes)
+    // rather than a measured bound. Going over it only costs a relocation.
+    pub const TABLE_CAPACITY: usize = 8;
+}
+
+// These structs are handed to the game as-is, so their layout is checked at
+// compile time: a wrong size or offset is an array length mismatch.
+macro_rules! offset_of {
+    ($ty:ty, $field:ident) => {{
+        let base = mem::MaybeUninit::<$ty>::uninit();
+        let base_ptr = &base as *const mem::MaybeUninit<$ty> as *const $ty;
+        #[allow(unused_unsafe)]
+        unsafe {
+            let field_ptr = &(*base_ptr).$field as *const _ as *const u8;
+            field_ptr.offset_from(base_ptr as *const u8) as usize
+        }
+    }};
+}
+
+const _: [(); 0x28] = [(); mem::size_of::<KartStats>()];
+const _: [(); 0x0] = [(); offset_of!(KartStats, accel)];
+const _: [(); 0x4] = [(); offset_of!(KartStats, brake_force)];
+const _: [(); 0x8] = [(); offset_of!(KartStats, no_accel_force)];
+const _: [(); 0xc] = [(); offset_of!(KartStats, max_drive_speed)];
+const _: [(); 0x10] = [(); offset_of!(KartStats, gravity)];
+const _: [(); 0x14] = [(); offset_of!(KartStats, unknown1)];
+const _: [(); 0x18] = [(); offset_of!(KartStats, drift_factor)];
+const _: [(); 0x1c] = [(); offset_of!(KartStats, drift_threshold)];
+const _: [(); 0x20] = [(); offset_of!(KartStats, unknown2)];
+const _: [(); 0x24] = [(); offset_of!(KartStats, hard_speed_cap)];
+
+const _: [(); 0x84] = [(); mem::size_of::<KartDlc>()];
+const _: [(); 0x0] = [(); offset_of!(KartDlc, stats)];
+const _: [(); 0x28] = [(); offset_of!(KartDlc, autorun_slot_handicap_1)];
+const _: [(); 0x2c] = [(); offset_of!(KartDlc, autorun_rank_handicap_1)];
+const _: [(); 0x30] = [(); offset_of!(KartDlc, autorun_not_first_handicap_1)];
+const _: [(); 0x34] = [(); offset_of!(KartDlc, autorun_slot_handicap_2)];
+const _: [(); 0x38] = [(); offset_of!(KartDlc, autorun_rank_handicap_2)];
+const _: [(); 0x3c] = [(); offset_of!(KartDlc, autorun_not_first_handicap_2)];
+const _: [(); 0x40] = [(); offset_of!(KartDlc, ai_use_dlc_kart)];
+const _: [(); 0x44] = [(); offset_of!(KartDlc, song_name)];
+
+const _: [(); 0x280] = [(); mem::size_of::<DlcText>()];
+
+const _: [(); DlcDescriptor::SIZE] = [(); mem::size_of::<DlcDescriptor>()];
+const _: [(); 0x0] = [(); offset_of!(DlcDescriptor, unknown1)];
+const _: [(); 0x4] = [(); offset_of!(DlcDescriptor, event_id)];
+const _: [(); 0x8] = [(); offset_of!(DlcDescriptor, unknown2)];
+const _: [(); 0xc] = [(); offset_of!(DlcDescriptor, unknown3)];
+const _: [(); 0x10] = [(); offset_of!(DlcDescriptor, unknown4)];
+const _: [(); 0x14] = [(); offset_of!(DlcDescriptor, unknown5)];
+const _: [(); 0x18] = [(); offset_of!(DlcDescriptor, dlc_type)];
+const _: [(); 0x1c] = [(); offset_of!(DlcDescriptor, levels)];
+const _: [(); 0x3c] = [(); offset_of!(DlcDescriptor, text)];
+
+// Where the unknown fields sit, for experiment::UnknownField.
+impl KartStats {
+    pub const UNKNOWN1_OFFSET: u32 = offset_of!(KartStats, unknown1) as u32;
+    pub const UNKNOWN2_OFFSET: u32 = offset_of!(KartStats, unknown2) as u32;
+}
+
+impl DlcDescriptor {
+    pub const UNKNOWN1_OFFSET: u32 = offset_of!(DlcDescriptor, unknown1) as u32;
+    pub const UNKNOWN2_OFFSET: u32 = offset_of!(DlcDescriptor, unknown2) as u32;
+    pub const UNKNOWN3_OFFSET: u32 = offset_of!(DlcDescriptor, unknown3) as u32;
+    pub const UNKNOWN4_OFFSET: u32 = offset_of!(DlcDescriptor, unknown4) as u32;
+    pub const UNKNOWN5_OFFSET: u32 = offset_of!(DlcDescriptor, unknown5) as u32;
+}
@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::model::KartDlc;
+
+pub const SONG_NAME_LEN: usize = 64;
+
+// In config.toml, under [music].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MusicConfig {
+    // Relative to the game directory.
+    pub adx_dir: String,
+    // Played instead of a song that isn't there.
+    pub fallback_song: String,
+}
+
+impl Default for MusicConfig {
+    fn default() -> MusicConfig {
+        MusicConfig {
+            adx_dir: "resource/gd_PC/ADX/".to_string(),
+            fallback_song: "a_mine.adx".to_string(),
+        }
+    }
+}
+
+impl MusicConfig {
+    pub fn song_exists(&self, name: &str) -> bool {
+        !name.is_empty() && Path::new(&self.adx_dir).join(name).is_file()
+    }
+}
+
+impl KartDlc {
+    // File name of the BGM under the ADX directory, e.g. `a_mine.adx`.
+    pub fn song_name(&self) -> String {
+        let end = self.song_name.iter().position(|&b| b == 0).unwrap_or(SONG_NAME_LEN);
+        String::from_utf8_lossy(&self.song_name[..end]).into_owned()
+    }
+
+    pub fn set_song_name(&mut self, name: &str) -> Result<()> {
+        let reason = if !name.is_ascii() {
+            "must be ASCII"
+        } else if name.len() >= SONG_NAME_LEN {
+            "must be under 64 bytes"
+        } else if name.contains(|c| c == '\0' || c == '/' || c == '\\') {
+            "must be a bare file name"
+        } else {
+            let mut song_name = [0; SONG_NAME_LEN];
+            song_name[..name.len()].copy_from_slice(name.as_bytes());
+            self.song_name = song_name;
+            return Ok(());
+        };
+        Err(Error::Text {
+            field: "song_name".to_string(),
+            reason: format!("{:?} {}", name, reason),
+        })
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use std::mem;
 use std::ffi::CStr;
+use std::io;
 use std::vec::IntoIter;
 
 use winapi::ctypes::c_void;
@@ -11,18 +12,23 @@ use winapi::um::processthreadsapi;
 use winapi::um::psapi;
 use winapi::um::winnt::{HANDLE, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ, PROCESS_VM_WRITE};
 
+use crate::error::{Error, Result};
+
 const PROCESS_BUFFER_LEN: usize = 1024;
 
 #[derive(Clone,Copy,Debug)]
 pub struct ProcessHandle(HANDLE);
 
 impl ProcessHandle {
-    fn open_process(id: ProcessId, mode: u32) -> Result<ProcessHandle, &'static str> {
+    fn open_process(id: ProcessId, mode: u32) -> Result<ProcessHandle> {
         let handle;
         unsafe {
             handle = processthreadsapi::OpenProcess(mode, false as i32, id.0);
             if handle == NULL {
-                return Err("could not open process");
+                return Err(Error::OpenProcess {
+                    pid: id.0,
+                    error: io::Error::last_os_error(),
+                });
             }
         }
         Ok(ProcessHandle(handle))
@@ -36,32 +42,32 @@ impl ProcessHandle {
         ProcessHandle(handle)
     }
 
-    pub fn open_process_read_info(id: ProcessId) -> Result<ProcessHandle, &'static str> {
+    pub fn open_process_read_info(id: ProcessId) -> Result<ProcessHandle> {
         Self::open_process(id, PROCESS_QUERY_INFORMATION | PROCESS_VM_READ | PROCESS_VM_WRITE)
     }
 
-    pub fn get_name(&self) -> Result<String, &'static str> {
+    pub fn get_name(&self) -> Result<String> {
         let name;
         unsafe {
             let mut module = mem::uninitialized();
             let mut bytes_needed = mem::uninitialized();
             let result = psapi::EnumProcessModules(self.0, &mut module as *mut HMODULE, mem::size_of::<HMODULE>() as u32, &mut bytes_needed as *mut u32);
             if result == 0 {
-                return Err("error in EnumProcessModules");
+                return Err(Error::ProcessName(format!("EnumProcessModules: {}", io::Error::last_os_error())));
             }
             let mut name_buffer = [0i8; MAX_PATH];
             let bytes_in_str = psapi::GetModuleBaseNameA(self.0, module, &mut name_buffer[0] as *mut i8, MAX_PATH as u32);
             let name_buffer: [u8; MAX_PATH] = mem::transmute(name_buffer);
             name = CStr::from_bytes_with_nul(&name_buffer[.. bytes_in_str as usize + 1])
-                .map_err(|_| "error converting process name")?
+                .map_err(|e| Error::ProcessName(e.to_string()))?
                 .to_str()
-                .map_err(|_| "error converting process name")?
+                .map_err(|e| Error::ProcessName(e.to_string()))?
                 .to_string();
         }
         Ok(name)
     }
 
-    pub fn from_name_filter<F>(mut filter: F) -> Result<Option<ProcessHandle>, &'static str>
+    pub fn from_name_filter<F>(mut filter: F) -> Result<Option<ProcessHandle>>
         where F: FnMut(String) -> bool,
     {
         let mut processes = ProcessIterator::new()?
@@ -78,38 +84,42 @@ impl ProcessHandle {
         Ok(processes.next())
     }
 
-    pub fn read_data(&self, address: u32, buf: &mut [u8]) -> Result<usize, &'static str> {
+    pub fn read_data(&self, address: u32, buf: &mut [u8]) -> Result<usize> {
         let mut bytes_read;
         unsafe {
             bytes_read = mem::uninitialized();
-            let address = mem::transmute(address);
+            let address_ptr = mem::transmute(address);
             let buf_addr = buf.as_mut_ptr() as *mut c_void;
-            let result = memoryapi::ReadProcessMemory(self.0, address, buf_addr, buf.len(), &mut bytes_read as *mut usize);
+            let result = memoryapi::ReadProcessMemory(self.0, address_ptr, buf_addr, buf.len(), &mut bytes_read as *mut usize);
             if result == 0 {
-                return Err("Error in ReadProcessMemory");
+                return Err(Error::MemoryRead {
+                    address: address,
+                    len: buf.len(),
+                });
             }
         }
         Ok(bytes_read)
     }
 
-    pub fn read_type<T>(&self, address: u32, data: &mut T) -> Result<(), &'static str> {
+    pub fn read_type<T>(&self, address: u32, data: &mut T) -> Result<()> {
         unsafe {
             let mut bytes_read = mem::uninitialized();
             let data_size = mem::size_of::<T>();
-            let address = mem::transmute(address);
+            let address_ptr = mem::transmute(address);
             let buf_addr = data as *mut T as *mut c_void;
-            let result = memoryapi::ReadProcessMemory(self.0, address, buf_addr, data_size, &mut bytes_read as *mut usize);
-            if result == 0 {
-                Err("error in ReadProcessMemory")
-            } else if data_size != bytes_read {
-                Err("couldn't read enough data")
+            let result = memoryapi::ReadProcessMemory(self.0, address_ptr, buf_addr, data_size, &mut bytes_read as *mut usize);
+            if result == 0 || data_size != bytes_read {
+                Err(Error::MemoryRead {
+                    address: address,
+                    len: data_size,
+                })
             } else {
                 Ok(())
             }
         }
     }
 
-    pub fn read_copy<T>(&self, address: u32) -> Result<T, &'static str>
+    pub fn read_copy<T>(&self, address: u32) -> Result<T>
     where
         T: Copy,
     {
@@ -117,69 +127,89 @@ impl ProcessHandle {
             let mut data = mem::uninitialized();
             let mut bytes_read = mem::uninitialized();
             let data_size = mem::size_of::<T>();
-            let address = mem::transmute(address);
+            let address_ptr = mem::transmute(address);
             let buf_addr = &mut data as *mut T as *mut c_void;
-            let result = memoryapi::ReadProcessMemory(self.0, address, buf_addr, data_size, &mut bytes_read as *mut usize);
-            if result == 0 {
-                Err("error in ReadProcessMemory")
-            } else if data_size != bytes_read {
-                Err("couldn't read enough data")
+            let result = memoryapi::ReadProcessMemory(self.0, address_ptr, buf_addr, data_size, &mut bytes_read as *mut usize);
+            if result == 0 || data_size != bytes_read {
+                Err(Error::MemoryRead {
+                    address: address,
+                    len: data_size,
+                })
             } else {
                 Ok(data)
             }
         }
     }
 
-    pub fn write_data(&self, address: u32, buf: &[u8]) -> Result<usize, &'static str> {
+    pub fn write_data(&self, address: u32, buf: &[u8]) -> Result<usize> {
         let mut bytes_written;
         unsafe {
             bytes_written = mem::uninitialized();
-            let address = mem::transmute(address);
+            let address_ptr = mem::transmute(address);
             let buf_addr = buf.as_ptr() as *const c_void;
-            let result = memoryapi::WriteProcessMemory(self.0, address, buf_addr, buf.len(), &mut bytes_written as *mut usize);
+            let result = memoryapi::WriteProcessMemory(self.0, address_ptr, buf_addr, buf.len(), &mut bytes_written as *mut usize);
             if result == 0 {
-                return Err("Error in WriteProcessMemory");
+                return Err(Error::MemoryWrite {
+                    address: address,
+                    len: buf.len(),
+                });
             }
         }
         Ok(bytes_written)
     }
 
-    pub fn write_type<T>(&self, address: u32, data: &T) -> Result<(), &'static str> {
+    pub fn write_type<T>(&self, address: u32, data: &T) -> Result<()> {
         unsafe {
             let mut bytes_written = mem::uninitialized();
             let data_size = mem::size_of::<T>();
-            let address = mem::transmute(address);
+            let address_ptr = mem::transmute(address);
             let buf_addr = data as *const T as *const c_void;
-            let result = memoryapi::WriteProcessMemory(self.0, address, buf_addr, data_size, &mut bytes_written as *mut usize);
-            if result == 0 {
-                Err("error in WriteProcessMemory")
-            } else if data_size != bytes_written {
-                Err("couldn't write enough data")
+            let result = memoryapi::WriteProcessMemory(self.0, address_ptr, buf_addr, data_size, &mut bytes_written as *mut usize);
+            if result == 0 || data_size != bytes_written {
+                Err(Error::MemoryWrite {
+                    address: address,
+                    len: data_size,
+                })
             } else {
                 Ok(())
             }
         }
     }
 
-    pub fn write_copy<T>(&self, address: u32, data: T) -> Result<(), &'static str>
+    pub fn write_copy<T>(&self, address: u32, data: T) -> Result<()>
     where
         T: Copy,
     {
         unsafe {
             let mut bytes_written = mem::uninitialized();
             let data_size = mem::size_of::<T>();
-            let address = mem::transmute(address);
+            let address_ptr = mem::transmute(address);
             let buf_addr = &data as *const T as *const c_void;
-            let result = memoryapi::WriteProcessMemory(self.0, address, buf_addr, data_size, &mut bytes_written as *mut usize);
-            if result == 0 {
-                Err("error in WriteProcessMemory")
-            } else if data_size != bytes_written {
-                Err("couldn't write enough data")
+            let result = memoryapi::WriteProcessMemory(self.0, address_ptr, buf_addr, data_size, &mut bytes_written as *mut usize);
+            if result == 0 || data_size != bytes_written {
+                Err(Error::MemoryWrite {
+                    address: address,
+                    len: data_size,
+                })
             } else {
                 Ok(())
             }
         }
     }
+
+    // Reads memory back to make sure a patch actually landed.
+    pub fn verify_data(&self, address: u32, expected: &[u8]) -> Result<()> {
+        let mut found = vec![0; expected.len()];
+        self.read_data(address, &mut found)?;
+        if found != expected {
+            return Err(Error::PatchMismatch {
+                address: address,
+                expected: expected.to_vec(),
+                found: found,
+            });
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone,Copy,Debug)]
@@ -191,7 +221,7 @@ pub struct ProcessIterator {
 }
 
 impl ProcessIterator {
-    pub fn new() -> Result<ProcessIterator, &'static str> {
+    pub fn new() -> Result<ProcessIterator> {
         let mut buffer = vec![0; PROCESS_BUFFER_LEN];
 
         unsafe {
@@ -199,7 +229,7 @@ impl ProcessIterator {
             let mut returned_bytes = 0u32;
             let result = psapi::EnumProcesses(buf_ptr, (PROCESS_BUFFER_LEN * mem::size_of::<u32>()) as u32, &mut returned_bytes as *mut u32);
             if result == 0 {
-                return Err("Error in EnumProcess");
+                return Err(Error::EnumProcesses(io::Error::last_os_error()));
             }
             buffer.set_len(returned_bytes as usize / mem::size_of::<u32>());
         }
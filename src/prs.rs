@@ -0,0 +1,412 @@
+// PRS encoder. The stream is a sequence of commands, each introduced by
+// control bits packed LSB first into bytes interleaved with the data:
+//   1          literal byte follows
+//   0 0 b b    short copy, size bb + 2, one byte of offset - 0x100
+//   0 1        long copy, u16 of (offset << 3 | size - 2); size 0 means an
+//              extra byte of size - 1 follows, and an all zero u16 ends the stream
+
+const SHORT_MAX_DIST: usize = 0x100;
+const SHORT_MIN_LEN: usize = 2;
+const SHORT_MAX_LEN: usize = 5;
+// An offset of -0x2000 would make an extended copy encode as the end marker.
+const LONG_MAX_DIST: usize = 0x1fff;
+const LONG_MIN_LEN: usize = 3;
+const LONG_MAX_SMALL_LEN: usize = 9;
+const LONG_MAX_LEN: usize = 0x100;
+
+// Sizes in bits, control bits included.
+const LITERAL_COST: u32 = 1 + 8;
+const SHORT_COST: u32 = 4 + 8;
+const LONG_COST: u32 = 2 + 16;
+const LONG_EXT_COST: u32 = 2 + 16 + 8;
+
+const HASH_BITS: u32 = 16;
+const FAST_CHAIN_DEPTH: usize = 32;
+const MAX_CHAIN_DEPTH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    // Literals only. Fastest, but larger than the input.
+    None,
+    // Greedy matching with a shallow search.
+    Fast,
+    // Searches the whole window and picks the cheapest encoding overall.
+    Max,
+}
+
+struct PrsWriter {
+    out: Vec<u8>,
+    control_idx: usize,
+    control_bit: u32,
+}
+
+impl PrsWriter {
+    fn new() -> PrsWriter {
+        PrsWriter {
+            out: Vec::new(),
+            control_idx: 0,
+            control_bit: 8,
+        }
+    }
+
+    fn put_bit(&mut self, bit: bool) {
+        // The decoder fetches a new control byte only when it runs out of
+        // bits, so the byte has to go wherever the stream is at that point.
+        if self.control_bit == 8 {
+            self.control_idx = self.out.len();
+            self.out.push(0);
+            self.control_bit = 0;
+        }
+        if bit {
+            self.out[self.control_idx] |= 1 << self.control_bit;
+        }
+        self.control_bit += 1;
+    }
+
+    fn literal(&mut self, byte: u8) {
+        self.put_bit(true);
+        self.out.push(byte);
+    }
+
+    fn copy(&mut self, dist: usize, len: usize) {
+        if dist <= SHORT_MAX_DIST && len >= SHORT_MIN_LEN && len <= SHORT_MAX_LEN {
+            let size = len - SHORT_MIN_LEN;
+            self.put_bit(false);
+            self.put_bit(false);
+            self.put_bit(size & 2 != 0);
+            self.put_bit(size & 1 != 0);
+            self.out.push((SHORT_MAX_DIST - dist) as u8);
+        } else {
+            let offset = ((0x2000 - dist) << 3) as u16;
+            self.put_bit(false);
+            self.put_bit(true);
+            if len <= LONG_MAX_SMALL_LEN {
+                let word = offset | (len - 2) as u16;
+                self.out.push(word as u8);
+                self.out.push((word >> 8) as u8);
+            } else {
+                self.out.push(offset as u8);
+                self.out.push((offset >> 8) as u8);
+                self.out.push((len - 1) as u8);
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.put_bit(false);
+        self.put_bit(true);
+        self.out.push(0);
+        self.out.push(0);
+        self.out
+    }
+}
+
+// Hash chains over 3 byte prefixes for finding long copies.
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    chain_depth: usize,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8], chain_depth: usize) -> MatchFinder<'a> {
+        MatchFinder {
+            data: data,
+            head: vec![usize::max_value(); 1 << HASH_BITS],
+            prev: vec![usize::max_value(); data.len()],
+            chain_depth: chain_depth,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> Option<usize> {
+        if pos + 3 > self.data.len() {
+            return None;
+        }
+        let key = (self.data[pos] as u32) << 16 | (self.data[pos + 1] as u32) << 8 | self.data[pos + 2] as u32;
+        Some((key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize)
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if let Some(hash) = self.hash(pos) {
+            self.prev[pos] = self.head[hash];
+            self.head[hash] = pos;
+        }
+    }
+
+    fn match_len(&self, pos: usize, dist: usize, max_len: usize) -> usize {
+        let limit = max_len.min(self.data.len() - pos);
+        let mut len = 0;
+        while len < limit && self.data[pos + len] == self.data[pos + len - dist] {
+            len += 1;
+        }
+        len
+    }
+
+    // Longest copy usable as a long command, as (dist, len).
+    fn longest(&self, pos: usize) -> Option<(usize, usize)> {
+        let hash = self.hash(pos)?;
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[hash];
+        let mut depth = 0;
+        while candidate != usize::max_value() && depth < self.chain_depth {
+            let dist = pos - candidate;
+            if dist > LONG_MAX_DIST {
+                break;
+            }
+            let len = self.match_len(pos, dist, LONG_MAX_LEN);
+            if len >= LONG_MIN_LEN && best.map_or(true, |(_, best_len)| len > best_len) {
+                best = Some((dist, len));
+                if len == LONG_MAX_LEN {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+            depth += 1;
+        }
+        best
+    }
+
+    // Longest copy usable as a short command. The window is small enough to
+    // just scan, which also finds 2 byte matches the hash can't.
+    fn longest_short(&self, pos: usize) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        for dist in 1..=SHORT_MAX_DIST.min(pos) {
+            let len = self.match_len(pos, dist, SHORT_MAX_LEN);
+            if len >= SHORT_MIN_LEN && best.map_or(true, |(_, best_len)| len > best_len) {
+                best = Some((dist, len));
+                if len == SHORT_MAX_LEN {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+fn copy_cost(dist: usize, len: usize) -> u32 {
+    if dist <= SHORT_MAX_DIST && len <= SHORT_MAX_LEN {
+        SHORT_COST
+    } else if len <= LONG_MAX_SMALL_LEN {
+        LONG_COST
+    } else {
+        LONG_EXT_COST
+    }
+}
+
+fn encode_fast(data: &[u8], writer: &mut PrsWriter) {
+    let mut finder = MatchFinder::new(data, FAST_CHAIN_DEPTH);
+    let mut pos = 0;
+    while pos < data.len() {
+        let best = match (finder.longest(pos), finder.longest_short(pos)) {
+            (Some(long), Some(short)) => Some(if short.1 >= long.1 { short } else { long }),
+            (long, short) => long.or(short),
+        };
+        let advance = match best {
+            Some((dist, len)) if copy_cost(dist, len) < LITERAL_COST * len as u32 => {
+                writer.copy(dist, len);
+                len
+            }
+            _ => {
+                writer.literal(data[pos]);
+                1
+            }
+        };
+        for _ in 0..advance {
+            finder.insert(pos);
+            pos += 1;
+        }
+    }
+}
+
+// Optimal parse: find the cheapest way to encode every suffix of the data.
+fn encode_max(data: &[u8], writer: &mut PrsWriter) {
+    let mut finder = MatchFinder::new(data, MAX_CHAIN_DEPTH);
+    let mut long_matches = Vec::with_capacity(data.len());
+    let mut short_matches = Vec::with_capacity(data.len());
+    for pos in 0..data.len() {
+        long_matches.push(finder.longest(pos));
+        short_matches.push(finder.longest_short(pos));
+        finder.insert(pos);
+    }
+
+    // cost[pos] is the size in bits of the best encoding of data[pos..],
+    // and step[pos] the (dist, len) of its first command (dist 0 is a literal).
+    let mut cost = vec![0u32; data.len() + 1];
+    let mut step = vec![(0usize, 1usize); data.len()];
+    for pos in (0..data.len()).rev() {
+        cost[pos] = LITERAL_COST + cost[pos + 1];
+        step[pos] = (0, 1);
+
+        if let Some((dist, max_len)) = short_matches[pos] {
+            for len in SHORT_MIN_LEN..=max_len {
+                let total = SHORT_COST + cost[pos + len];
+                if total < cost[pos] {
+                    cost[pos] = total;
+                    step[pos] = (dist, len);
+                }
+            }
+        }
+        if let Some((dist, max_len)) = long_matches[pos] {
+            for len in LONG_MIN_LEN..=max_len {
+                let total = copy_cost(LONG_MAX_DIST, len) + cost[pos + len];
+                if total < cost[pos] {
+                    cost[pos] = total;
+                    step[pos] = (dist, len);
+                }
+            }
+        }
+    }
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let (dist, len) = step[pos];
+        if dist == 0 {
+            writer.literal(data[pos]);
+        } else {
+            writer.copy(dist, len);
+        }
+        pos += len;
+    }
+}
+
+pub fn encode(data: &[u8], compression: Compression) -> Vec<u8> {
+    let mut writer = PrsWriter::new();
+    match compression {
+        Compression::None => {
+            for &byte in data {
+                writer.literal(byte);
+            }
+        }
+        Compression::Fast => encode_fast(data, &mut writer),
+        Compression::Max => encode_max(data, &mut writer),
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use prs_util::decoder::Decoder;
+
+    use super::*;
+
+    const ALL: [Compression; 3] = [Compression::None, Compression::Fast, Compression::Max];
+
+    fn decode(stream: &[u8]) -> Vec<u8> {
+        Decoder::new(Cursor::new(stream)).decode_to_vec().unwrap()
+    }
+
+    fn check_round_trip(data: &[u8]) {
+        for &compression in ALL.iter() {
+            let stream = encode(data, compression);
+            assert!(decode(&stream) == data, "{:?}, 0x{:x} bytes", compression, data.len());
+        }
+    }
+
+    // Bytes that rarely repeat, so copies only show up where a test puts them.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn repeat_run(data: &mut Vec<u8>, start: usize, len: usize) {
+        let run = data[start..start + len].to_vec();
+        data.extend(run);
+    }
+
+    // Writes the prefix as literals followed by the given (dist, len) copies,
+    // bypassing the match finders, and checks the decoder expands them.
+    fn check_commands(prefix: &[u8], copies: &[(usize, usize)]) {
+        let mut writer = PrsWriter::new();
+        let mut expected = Vec::new();
+        for &byte in prefix {
+            writer.literal(byte);
+            expected.push(byte);
+        }
+        for &(dist, len) in copies {
+            writer.copy(dist, len);
+            for _ in 0..len {
+                let byte = expected[expected.len() - dist];
+                expected.push(byte);
+            }
+        }
+        assert!(decode(&writer.finish()) == expected, "{} literals, copies {:?}", prefix.len(), copies);
+    }
+
+    #[test]
+    fn empty() {
+        for &compression in ALL.iter() {
+            let stream = encode(&[], compression);
+            assert_eq!(stream, [0x02, 0x00, 0x00]);
+            assert!(decode(&stream).is_empty());
+        }
+    }
+
+    #[test]
+    fn window_edges() {
+        let prefix = noise(0x2000, 1);
+        check_commands(&prefix, &[(SHORT_MAX_DIST, SHORT_MAX_LEN), (SHORT_MAX_DIST + 1, 3)]);
+        check_commands(&prefix, &[(LONG_MAX_DIST, LONG_MIN_LEN), (LONG_MAX_DIST, LONG_MAX_LEN)]);
+
+        // The same run again exactly 0x100 and 0x1fff bytes later.
+        let mut data = noise(SHORT_MAX_DIST, 2);
+        repeat_run(&mut data, 0, SHORT_MAX_LEN);
+        check_round_trip(&data);
+        let mut data = noise(LONG_MAX_DIST, 3);
+        repeat_run(&mut data, 0, LONG_MAX_LEN);
+        check_round_trip(&data);
+    }
+
+    #[test]
+    fn copy_lengths() {
+        let prefix = noise(0x300, 4);
+        for &len in [LONG_MAX_SMALL_LEN, LONG_MAX_SMALL_LEN + 1, LONG_MAX_LEN].iter() {
+            check_commands(&prefix, &[(0x200, len)]);
+            check_commands(&prefix, &[(0x20, len)]);
+
+            let mut data = noise(0x200, len as u32);
+            repeat_run(&mut data, 0x10, len);
+            data.extend(noise(0x10, 5));
+            check_round_trip(&data);
+        }
+    }
+
+    #[test]
+    fn overlapping_runs() {
+        check_commands(b"a", &[(1, SHORT_MAX_LEN), (1, LONG_MAX_LEN)]);
+        check_commands(b"abc", &[(3, LONG_MAX_SMALL_LEN + 1), (2, 0x80)]);
+        check_round_trip(&[0; 0x1000]);
+        check_round_trip(&b"ab".iter().cycle().take(0x333).cloned().collect::<Vec<u8>>());
+    }
+
+    // Every command starts at each bit of a control byte, so the next control
+    // byte lands between a command's bits, and before its data bytes.
+    #[test]
+    fn control_byte_mid_copy() {
+        let prefix = noise(0x40, 6);
+        for literals in 0x30..0x38 {
+            check_commands(&prefix[..literals], &[(0x20, 2)]);
+            check_commands(&prefix[..literals], &[(0x20, LONG_MAX_SMALL_LEN)]);
+            check_commands(&prefix[..literals], &[(0x20, LONG_MAX_SMALL_LEN + 1)]);
+        }
+    }
+
+    #[test]
+    fn mixed_data() {
+        let mut data = noise(0x4000, 7);
+        for (idx, byte) in data.iter_mut().enumerate() {
+            if idx % 7 != 0 {
+                *byte = (idx / 13 % 251) as u8;
+            }
+        }
+        check_round_trip(&data);
+        assert!(encode(&data, Compression::Max).len() <= encode(&data, Compression::Fast).len());
+    }
+}
@@ -0,0 +1,257 @@
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BE, LE};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+pub const HEADER_LEN: usize = 32;
+// The first header field is the object count.
+const COUNT_LEN: usize = 4;
+pub const OBJECT_SIZE: usize = 32;
+
+// One placed object. Rotations are BAMS (0x10000 to a full turn).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SetObject {
+    pub id: u16,
+    pub rotation: [u16; 3],
+    pub position: [f32; 3],
+    // Scale for most objects, but some read these as plain parameters.
+    pub scale: [f32; 3],
+}
+
+impl SetObject {
+    fn read_from<B>(mut data: &[u8]) -> Result<SetObject>
+    where
+        B: ByteOrder,
+    {
+        let mut object = SetObject::default();
+        object.id = data.read_u16::<B>()?;
+        for angle in object.rotation.iter_mut() {
+            *angle = data.read_u16::<B>()?;
+        }
+        for coord in object.position.iter_mut() {
+            *coord = data.read_f32::<B>()?;
+        }
+        for value in object.scale.iter_mut() {
+            *value = data.read_f32::<B>()?;
+        }
+        Ok(object)
+    }
+
+    fn write_to<B>(&self, data: &mut Vec<u8>) -> Result<()>
+    where
+        B: ByteOrder,
+    {
+        data.write_u16::<B>(self.id)?;
+        for &angle in self.rotation.iter() {
+            data.write_u16::<B>(angle)?;
+        }
+        for &coord in self.position.iter() {
+            data.write_f32::<B>(coord)?;
+        }
+        for &value in self.scale.iter() {
+            data.write_f32::<B>(value)?;
+        }
+        Ok(())
+    }
+}
+
+// The set file of a kart course. DLC files carry it big endian, the way the
+// Dreamcast read it; the PC game wants the objects little endian.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SetFile {
+    // Big endian, as in the DLC file. It starts with the object count, which
+    // is recomputed from objects on write; the rest hasn't been worked out,
+    // so it is written back as-is. The little endian copy keeps the header
+    // big endian too, the way the loader has always handed it to the game.
+    // Normally HEADER_LEN bytes; it is padded or cut to that when there are
+    // objects after it.
+    pub header: Vec<u8>,
+    pub objects: Vec<SetObject>,
+}
+
+impl SetFile {
+    pub fn from_be_bytes(data: &[u8]) -> Result<SetFile> {
+        SetFile::read_from::<BE>(data)
+    }
+
+    // The form DLC files hold.
+    pub fn to_be_bytes(&self) -> Result<Vec<u8>> {
+        self.write_to::<BE>()
+    }
+
+    // The form the PC game reads.
+    pub fn to_le_bytes(&self) -> Result<Vec<u8>> {
+        self.write_to::<LE>()
+    }
+
+    // Returns the new object's index.
+    pub fn add(&mut self, object: SetObject) -> usize {
+        self.objects.push(object);
+        self.objects.len() - 1
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Option<SetObject> {
+        if idx < self.objects.len() {
+            Some(self.objects.remove(idx))
+        } else {
+            None
+        }
+    }
+
+    pub fn move_to(&mut self, idx: usize, position: [f32; 3]) -> Option<&mut SetObject> {
+        let object = self.objects.get_mut(idx)?;
+        object.position = position;
+        Some(object)
+    }
+
+    pub fn objects_with_id(&self, id: u16) -> impl Iterator<Item = (usize, &SetObject)> {
+        self.objects.iter().enumerate().filter(move |&(_, object)| object.id == id)
+    }
+
+    fn read_from<B>(data: &[u8]) -> Result<SetFile>
+    where
+        B: ByteOrder,
+    {
+        if data.len() <= HEADER_LEN {
+            return Ok(SetFile {
+                header: data.to_vec(),
+                objects: Vec::new(),
+            });
+        }
+        if (data.len() - HEADER_LEN) % OBJECT_SIZE != 0 {
+            return Err(Error::Malformed {
+                path: "set_file".to_string(),
+                reason: format!("0x{:x} bytes after the header is not a whole number of 0x{:x} byte objects",
+                    data.len() - HEADER_LEN, OBJECT_SIZE),
+            });
+        }
+        let objects = data[HEADER_LEN..].chunks(OBJECT_SIZE)
+            .map(SetObject::read_from::<B>)
+            .collect::<Result<_>>()?;
+        Ok(SetFile {
+            header: data[..HEADER_LEN].to_vec(),
+            objects: objects,
+        })
+    }
+
+    fn write_to<B>(&self) -> Result<Vec<u8>>
+    where
+        B: ByteOrder,
+    {
+        let mut data = self.header.clone();
+        if !self.objects.is_empty() {
+            data.resize(HEADER_LEN, 0);
+        }
+        if data.len() >= COUNT_LEN {
+            BE::write_u32(&mut data[..COUNT_LEN], self.objects.len() as u32);
+        }
+        for object in self.objects.iter() {
+            object.write_to::<B>(&mut data)?;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(id: u16) -> SetObject {
+        SetObject {
+            id: id,
+            rotation: [0, 0x4000, 0x8000],
+            position: [1.0, -2.5, 300.0],
+            scale: [1.0, 1.0, 0.5],
+        }
+    }
+
+    fn sample_bytes() -> Vec<u8> {
+        let mut data = vec![0; HEADER_LEN];
+        BE::write_u32(&mut data, 2);
+        data[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        for &id in [7, 9].iter() {
+            object(id).write_to::<BE>(&mut data).unwrap();
+        }
+        data
+    }
+
+    fn count(data: &[u8]) -> u32 {
+        BE::read_u32(data)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample_bytes();
+        let set_file = SetFile::from_be_bytes(&data).unwrap();
+        assert_eq!(set_file.objects, vec![object(7), object(9)]);
+        assert_eq!(set_file.to_be_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn little_endian_copy() {
+        let data = SetFile::from_be_bytes(&sample_bytes()).unwrap().to_le_bytes().unwrap();
+        assert_eq!(data.len(), HEADER_LEN + 2 * OBJECT_SIZE);
+        assert_eq!(&data[..HEADER_LEN], &sample_bytes()[..HEADER_LEN]);
+        assert_eq!(SetObject::read_from::<LE>(&data[HEADER_LEN..]).unwrap(), object(7));
+        assert_eq!(&data[HEADER_LEN..HEADER_LEN + 2], &[7, 0]);
+    }
+
+    #[test]
+    fn add_and_remove_update_the_count() {
+        let mut set_file = SetFile::from_be_bytes(&sample_bytes()).unwrap();
+        assert_eq!(set_file.add(object(11)), 2);
+        let data = set_file.to_be_bytes().unwrap();
+        assert_eq!(count(&data), 3);
+        assert_eq!(data.len(), HEADER_LEN + 3 * OBJECT_SIZE);
+        assert_eq!(&data[4..8], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(count(&set_file.to_le_bytes().unwrap()), 3);
+
+        assert_eq!(set_file.remove(0), Some(object(7)));
+        assert_eq!(set_file.remove(5), None);
+        let data = set_file.to_be_bytes().unwrap();
+        assert_eq!(count(&data), 2);
+        assert_eq!(SetFile::from_be_bytes(&data).unwrap().objects, vec![object(9), object(11)]);
+
+        set_file.remove(0);
+        set_file.remove(0);
+        let data = set_file.to_be_bytes().unwrap();
+        assert_eq!(data.len(), HEADER_LEN);
+        assert_eq!(count(&data), 0);
+    }
+
+    #[test]
+    fn stale_count_is_fixed() {
+        let mut data = sample_bytes();
+        BE::write_u32(&mut data, 5);
+        let set_file = SetFile::from_be_bytes(&data).unwrap();
+        assert_eq!(count(&set_file.to_be_bytes().unwrap()), 2);
+    }
+
+    #[test]
+    fn short_header_gets_padded() {
+        let mut set_file = SetFile::default();
+        assert!(set_file.to_be_bytes().unwrap().is_empty());
+        set_file.add(object(1));
+        let data = set_file.to_be_bytes().unwrap();
+        assert_eq!(data.len(), HEADER_LEN + OBJECT_SIZE);
+        assert_eq!(count(&data), 1);
+    }
+
+    #[test]
+    fn edit_objects() {
+        let mut set_file = SetFile::from_be_bytes(&sample_bytes()).unwrap();
+        set_file.add(object(7));
+        let ids: Vec<_> = set_file.objects_with_id(7).map(|(idx, _)| idx).collect();
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(set_file.move_to(1, [0.0, 1.0, 2.0]).unwrap().position, [0.0, 1.0, 2.0]);
+        assert!(set_file.move_to(3, [0.0; 3]).is_none());
+    }
+
+    #[test]
+    fn partial_object() {
+        let mut data = sample_bytes();
+        data.push(0);
+        let e = SetFile::from_be_bytes(&data).err().unwrap();
+        assert_eq!(e.to_string(), "set_file: 0x41 bytes after the header is not a whole number of 0x20 byte objects");
+    }
+}
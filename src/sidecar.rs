@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::config;
+use crate::dlc_data::DlcData;
+use crate::error::Result;
+use crate::text::{self, Language, TextField};
+
+// Per-DLC settings, e.g. `KartFZ.VMS.toml` next to `KartFZ.VMS`:
+//
+//     [text]
+//     fallback = "english"
+//
+//     [text.english]
+//     title = "\tFZ Course"
+//     description = "..."
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Sidecar {
+    pub text: TextConfig,
+    pub music: SidecarMusic,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SidecarMusic {
+    // Replaces the kart DLC's BGM, e.g. `song = "a_mine.adx"`.
+    pub song: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TextConfig {
+    // Empty language slots are filled from this one first.
+    pub fallback: Language,
+    // Replacement menu text, keyed by language and then field.
+    #[serde(flatten)]
+    pub overrides: BTreeMap<Language, BTreeMap<TextField, String>>,
+}
+
+impl Default for TextConfig {
+    fn default() -> TextConfig {
+        TextConfig {
+            fallback: Language::default(),
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl Sidecar {
+    pub fn path_for<P>(dlc_path: P) -> PathBuf
+    where
+        P: AsRef<Path>,
+    {
+        let mut path = OsString::from(dlc_path.as_ref());
+        path.push(".toml");
+        PathBuf::from(path)
+    }
+
+    // No sidecar means the defaults.
+    pub fn load<P>(path: P) -> Result<Sidecar>
+    where
+        P: AsRef<Path>,
+    {
+        config::load_toml_or_default(path)
+    }
+
+    // Overrides go in first so the fallback can spread them to other slots.
+    pub fn apply(&self, dlc: &mut DlcData) -> Result<()> {
+        if let (Some(song), Some(prs_data)) = (self.music.song.as_ref(), dlc.kart_mut()) {
+            prs_data.kart_dlc.set_song_name(song)?;
+        }
+        let info = match dlc.info_mut() {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        for (&language, fields) in self.text.overrides.iter() {
+            for (&field, value) in fields.iter() {
+                let truncated = info.dlc_texts[language.index()].set(field, language, value)?;
+                if truncated {
+                    warn!("{}.{} override is too long, truncated", language.name(), field.name());
+                }
+            }
+        }
+        text::fill_missing_text(&mut info.dlc_texts, self.text.fallback);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dlc_data::DlcInfo;
+    use crate::level::LevelId;
+    use crate::model::DlcText;
+    use crate::vms::{EyecatchType, VmsHeader};
+
+    fn sample_dlc() -> DlcData {
+        let mut dlc_texts = [DlcText::default(); 6];
+        for &language in Language::ALL.iter() {
+            dlc_texts[language.index()].set(TextField::Title, language, "Kart").unwrap();
+        }
+        DlcData::Type4 {
+            info: DlcInfo {
+                header: VmsHeader {
+                    vmu_description: [b' '; 16],
+                    boot_description: [b' '; 32],
+                    app_id: [0; 16],
+                    anim_speed: 0,
+                    eyecatch_type: EyecatchType::None,
+                    crc: 0,
+                    data_len: 0,
+                    reserved: [0; 20],
+                    palette: [0; 16],
+                    icons: Vec::new(),
+                    eyecatch: Vec::new(),
+                },
+                dlc_type: DlcData::TYPE4_TYPE,
+                dlc_texts: dlc_texts,
+                level_ids: [LevelId::default(); 8],
+            },
+            payload: Vec::new(),
+        }
+    }
+
+    fn text(dlc: &DlcData, field: TextField, language: Language) -> String {
+        dlc.info().unwrap().dlc_texts[language.index()].get(field, language)
+    }
+
+    #[test]
+    fn path_for() {
+        assert_eq!(Sidecar::path_for("DLC/KartFZ.VMS"), PathBuf::from("DLC/KartFZ.VMS.toml"));
+    }
+
+    #[test]
+    fn parse() {
+        let sidecar: Sidecar = toml::from_str("[text]\nfallback = \"german\"\n[text.french]\ntitle = \"Course\"\n").unwrap();
+        assert_eq!(sidecar.text.fallback, Language::German);
+        assert_eq!(sidecar.text.overrides[&Language::French][&TextField::Title], "Course");
+        assert!(sidecar.music.song.is_none());
+        assert_eq!(Sidecar::default().text.fallback, Language::English);
+    }
+
+    #[test]
+    fn overrides_replace_single_fields() {
+        let sidecar: Sidecar = toml::from_str(
+            "[text.english]\ntitle = \"FZ Course\"\n[text.japanese]\ntitle = \"コース\"\n").unwrap();
+        let mut dlc = sample_dlc();
+        sidecar.apply(&mut dlc).unwrap();
+        assert_eq!(text(&dlc, TextField::Title, Language::English), "FZ Course");
+        assert_eq!(text(&dlc, TextField::Title, Language::Japanese), "コース");
+        assert_eq!(text(&dlc, TextField::Title, Language::French), "Kart");
+    }
+
+    #[test]
+    fn overrides_spread_through_the_fallback() {
+        let sidecar: Sidecar = toml::from_str(
+            "[text]\nfallback = \"french\"\n[text.french]\nstage = \"Piste\"\n[text.english]\nstage = \"Track\"\n").unwrap();
+        let mut dlc = sample_dlc();
+        sidecar.apply(&mut dlc).unwrap();
+        assert_eq!(text(&dlc, TextField::Stage, Language::English), "Track");
+        assert_eq!(text(&dlc, TextField::Stage, Language::German), "Piste");
+        assert_eq!(text(&dlc, TextField::Stage, Language::Japanese), "Piste");
+    }
+
+    #[test]
+    fn long_overrides_are_truncated() {
+        let mut sidecar = Sidecar::default();
+        let mut fields = BTreeMap::new();
+        fields.insert(TextField::Description, "x".repeat(200));
+        sidecar.text.overrides.insert(Language::English, fields);
+        let mut dlc = sample_dlc();
+        sidecar.apply(&mut dlc).unwrap();
+        assert_eq!(text(&dlc, TextField::Description, Language::English), "x".repeat(text::TEXT_LEN - 1));
+    }
+
+    #[test]
+    fn bad_override_is_an_error() {
+        let sidecar: Sidecar = toml::from_str("[text.english]\ntitle = \"コース\"\n").unwrap();
+        let mut dlc = sample_dlc();
+        assert!(sidecar.apply(&mut dlc).is_err());
+    }
+
+    #[test]
+    fn song_needs_a_kart() {
+        let sidecar: Sidecar = toml::from_str("[music]\nsong = \"a_mine.adx\"\n").unwrap();
+        let mut dlc = sample_dlc();
+        sidecar.apply(&mut dlc).unwrap();
+        assert_eq!(text(&dlc, TextField::Title, Language::English), "Kart");
+    }
+}
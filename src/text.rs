@@ -0,0 +1,305 @@
+use encoding_rs::{Encoding, SHIFT_JIS, WINDOWS_1252};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::model::DlcText;
+
+// Buffers are NUL terminated, so one byte is always spare.
+pub const TEXT_LEN: usize = 128;
+
+// Order of the six DlcText slots.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Japanese,
+    English,
+    French,
+    Spanish,
+    German,
+    Italian,
+}
+
+impl Language {
+    pub const ALL: [Language; 6] = [
+        Language::Japanese,
+        Language::English,
+        Language::French,
+        Language::Spanish,
+        Language::German,
+        Language::Italian,
+    ];
+
+    pub fn from_index(idx: usize) -> Option<Language> {
+        Language::ALL.get(idx).cloned()
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::Japanese => "japanese",
+            Language::English => "english",
+            Language::French => "french",
+            Language::Spanish => "spanish",
+            Language::German => "german",
+            Language::Italian => "italian",
+        }
+    }
+
+    pub fn encoding(self) -> &'static Encoding {
+        match self {
+            Language::Japanese => SHIFT_JIS,
+            _ => WINDOWS_1252,
+        }
+    }
+}
+
+impl Default for Language {
+    fn default() -> Language {
+        Language::English
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextField {
+    Title,
+    DlcType,
+    Stage,
+    Character,
+    Description,
+}
+
+impl TextField {
+    pub const ALL: [TextField; 5] = [
+        TextField::Title,
+        TextField::DlcType,
+        TextField::Stage,
+        TextField::Character,
+        TextField::Description,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TextField::Title => "title",
+            TextField::DlcType => "dlc_type",
+            TextField::Stage => "stage",
+            TextField::Character => "character",
+            TextField::Description => "description",
+        }
+    }
+}
+
+impl DlcText {
+    pub fn field(&self, field: TextField) -> &[u8; TEXT_LEN] {
+        match field {
+            TextField::Title => &self.title,
+            TextField::DlcType => &self.dlc_type,
+            TextField::Stage => &self.stage,
+            TextField::Character => &self.character,
+            TextField::Description => &self.description,
+        }
+    }
+
+    pub fn field_mut(&mut self, field: TextField) -> &mut [u8; TEXT_LEN] {
+        match field {
+            TextField::Title => &mut self.title,
+            TextField::DlcType => &mut self.dlc_type,
+            TextField::Stage => &mut self.stage,
+            TextField::Character => &mut self.character,
+            TextField::Description => &mut self.description,
+        }
+    }
+
+    pub fn get(&self, field: TextField, language: Language) -> String {
+        decode_text(self.field(field), language)
+    }
+
+    // Returns whether the text had to be cut short to fit.
+    pub fn set(&mut self, field: TextField, language: Language, text: &str) -> Result<bool> {
+        let (encoded, truncated) = encode_text(text, language)
+            .map_err(|reason| Error::Text {
+                field: format!("{}.{}", language.name(), field.name()),
+                reason: reason,
+            })?;
+        *self.field_mut(field) = encoded;
+        Ok(truncated)
+    }
+}
+
+// Everything up to the first NUL. Bytes the codepage can't map come out as
+// U+FFFD rather than failing.
+pub fn decode_text(data: &[u8], language: Language) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or_else(|| data.len());
+    let (text, _) = language.encoding().decode_without_bom_handling(&data[..end]);
+    text.into_owned()
+}
+
+// Encodes text into a NUL padded buffer, cutting it at a character boundary
+// if it runs past TEXT_LEN - 1 bytes. Characters the codepage can't hold and
+// embedded NULs are rejected instead of being mangled.
+pub fn encode_text(text: &str, language: Language) -> ::std::result::Result<([u8; TEXT_LEN], bool), String> {
+    let encoding = language.encoding();
+    let mut buf = [0; TEXT_LEN];
+    let mut len = 0;
+    let mut truncated = false;
+    let mut char_buf = [0; 4];
+    for c in text.chars() {
+        if c == '\0' {
+            return Err("embedded NUL".to_string());
+        }
+        let (bytes, _, unmappable) = encoding.encode(c.encode_utf8(&mut char_buf));
+        if unmappable {
+            return Err(format!("{:?} can't be written as {}", c, encoding.name()));
+        }
+        if truncated || len + bytes.len() > TEXT_LEN - 1 {
+            truncated = true;
+            continue;
+        }
+        buf[len..len + bytes.len()].copy_from_slice(&bytes);
+        len += bytes.len();
+    }
+    Ok((buf, truncated))
+}
+
+// Fills empty fields from the primary language, or failing that the first
+// language that has the field in a form the slot's codepage can hold.
+pub fn fill_missing_text(texts: &mut [DlcText; 6], primary: Language) {
+    let mut sources = vec![primary];
+    sources.extend(Language::ALL.iter().cloned().filter(|&language| language != primary));
+    for &language in Language::ALL.iter() {
+        for &field in TextField::ALL.iter() {
+            if texts[language.index()].field(field)[0] != 0 {
+                continue;
+            }
+            let mut available = sources.iter()
+                .filter(|source| texts[source.index()].field(field)[0] != 0)
+                .peekable();
+            // Left blank everywhere, so there is nothing to fall back to.
+            if available.peek().is_none() {
+                continue;
+            }
+            let filled = available
+                .filter_map(|&source| {
+                    let text = texts[source.index()].get(field, source);
+                    encode_text(&text, language).ok().map(|(encoded, _)| (source, encoded))
+                })
+                .next();
+            match filled {
+                Some((source, encoded)) => {
+                    debug!("Filled {}.{} from {}", language.name(), field.name(), source.name());
+                    *texts[language.index()].field_mut(field) = encoded;
+                }
+                None => warn!("No text to fill {}.{} with", language.name(), field.name()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(texts: &mut [DlcText; 6], language: Language, text: &str) {
+        texts[language.index()].set(TextField::Title, language, text).unwrap();
+    }
+
+    fn title(texts: &[DlcText; 6], language: Language) -> String {
+        texts[language.index()].get(TextField::Title, language)
+    }
+
+    #[test]
+    fn shift_jis() {
+        let (buf, truncated) = encode_text("アKart", Language::Japanese).unwrap();
+        assert!(!truncated);
+        assert_eq!(&buf[..7], &[0x83, 0x41, b'K', b'a', b'r', b't', 0]);
+        assert_eq!(decode_text(&buf, Language::Japanese), "アKart");
+    }
+
+    #[test]
+    fn windows_1252() {
+        let (buf, _) = encode_text("Café €", Language::French).unwrap();
+        assert_eq!(&buf[..7], &[b'C', b'a', b'f', 0xe9, b' ', 0x80, 0]);
+        assert_eq!(decode_text(&buf, Language::French), "Café €");
+        // The same bytes mean something else to the Japanese slot.
+        assert_ne!(decode_text(&buf, Language::Japanese), "Café €");
+    }
+
+    #[test]
+    fn decode_stops_at_nul() {
+        assert_eq!(decode_text(b"Kart\0junk", Language::English), "Kart");
+        assert_eq!(decode_text(b"Kart", Language::English), "Kart");
+    }
+
+    #[test]
+    fn rejects_what_the_codepage_cant_hold() {
+        assert!(encode_text("アKart", Language::English).is_err());
+        assert!(encode_text("Ka\0rt", Language::English).is_err());
+        let mut text = DlcText::default();
+        let e = text.set(TextField::Stage, Language::German, "ア").err().unwrap();
+        assert!(e.to_string().contains("german.stage"));
+        assert_eq!(text.stage[0], 0);
+    }
+
+    #[test]
+    fn truncates_at_the_byte_limit() {
+        let fits = "a".repeat(TEXT_LEN - 1);
+        let (buf, truncated) = encode_text(&fits, Language::English).unwrap();
+        assert!(!truncated);
+        assert_eq!(decode_text(&buf, Language::English), fits);
+
+        let (buf, truncated) = encode_text(&"a".repeat(200), Language::English).unwrap();
+        assert!(truncated);
+        assert_eq!(decode_text(&buf, Language::English), fits);
+        assert_eq!(buf[TEXT_LEN - 1], 0);
+    }
+
+    #[test]
+    fn truncates_at_a_character_boundary() {
+        // Two bytes each, so only 63 fit in front of the NUL.
+        let (buf, truncated) = encode_text(&"ア".repeat(64), Language::Japanese).unwrap();
+        assert!(truncated);
+        assert_eq!(decode_text(&buf, Language::Japanese), "ア".repeat(63));
+        assert_eq!(&buf[TEXT_LEN - 2..], &[0, 0]);
+    }
+
+    #[test]
+    fn fill_prefers_the_primary_language() {
+        let mut texts = [DlcText::default(); 6];
+        set(&mut texts, Language::English, "English");
+        set(&mut texts, Language::German, "Deutsch");
+        fill_missing_text(&mut texts, Language::German);
+        assert_eq!(title(&texts, Language::English), "English");
+        assert_eq!(title(&texts, Language::German), "Deutsch");
+        assert_eq!(title(&texts, Language::French), "Deutsch");
+        assert_eq!(title(&texts, Language::Italian), "Deutsch");
+        assert_eq!(title(&texts, Language::Japanese), "Deutsch");
+    }
+
+    #[test]
+    fn fill_skips_text_the_slot_cant_hold() {
+        let mut texts = [DlcText::default(); 6];
+        set(&mut texts, Language::Japanese, "アKart");
+        set(&mut texts, Language::Spanish, "Carrera");
+        set(&mut texts, Language::Italian, "Corsa");
+        fill_missing_text(&mut texts, Language::Japanese);
+        // Japanese can't be written as Windows-1252, so the next language in
+        // slot order is used.
+        assert_eq!(title(&texts, Language::English), "Carrera");
+        assert_eq!(title(&texts, Language::German), "Carrera");
+        assert_eq!(title(&texts, Language::Italian), "Corsa");
+    }
+
+    #[test]
+    fn fill_leaves_fields_blank_everywhere_alone() {
+        let mut texts = [DlcText::default(); 6];
+        set(&mut texts, Language::English, "Kart");
+        fill_missing_text(&mut texts, Language::English);
+        for text in texts.iter() {
+            assert_eq!(text.description[0], 0);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use log::debug;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::model::{AiKart, KartDlc};
+
+// In config.toml:
+//
+//     [tuning]
+//     profile = "competitive"
+//     ai_kart = "own"
+//
+//     [tuning.profiles.competitive]
+//     max_drive_speed = { scale = 0.9 }
+//     autorun_rank_handicap_1 = { set = 0.0 }
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TuningConfig {
+    // Profile applied to every kart DLC, if any.
+    pub profile: Option<String>,
+    pub ai_kart: AiKartOverride,
+    pub profiles: BTreeMap<String, TuningProfile>,
+}
+
+// Forces what AI racers drive, whatever the DLC files say.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AiKartOverride {
+    File,
+    Dlc,
+    Own,
+}
+
+impl Default for AiKartOverride {
+    fn default() -> AiKartOverride {
+        AiKartOverride::File
+    }
+}
+
+impl AiKartOverride {
+    pub fn apply(self, kart: &mut KartDlc) {
+        match self {
+            AiKartOverride::File => (),
+            AiKartOverride::Dlc => kart.set_ai_kart(AiKart::DlcKart),
+            AiKartOverride::Own => kart.set_ai_kart(AiKart::OwnKart),
+        }
+    }
+}
+
+impl TuningConfig {
+    // The selected profile with its field names checked.
+    pub fn active(&self) -> Result<Option<Vec<(KartField, Adjustment)>>> {
+        let name = match self.profile {
+            Some(ref name) => name,
+            None => return Ok(None),
+        };
+        let profile = self.profiles.get(name)
+            .ok_or_else(|| Error::Config(format!("no tuning profile named {:?}", name)))?;
+        let mut adjustments = Vec::new();
+        for (field_name, &adjustment) in profile.iter() {
+            let field = KartField::from_name(field_name)
+                .ok_or_else(|| Error::Config(format!("tuning profile {:?}: no kart field named {:?}", name, field_name)))?;
+            adjustments.push((field, adjustment));
+        }
+        Ok(Some(adjustments))
+    }
+}
+
+// Keyed by KartField name.
+pub type TuningProfile = BTreeMap<String, Adjustment>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Adjustment {
+    Set(f32),
+    Scale(f32),
+}
+
+impl Adjustment {
+    pub fn apply(self, value: f32) -> f32 {
+        match self {
+            Adjustment::Set(new_value) => new_value,
+            Adjustment::Scale(factor) => value * factor,
+        }
+    }
+}
+
+// The KartDlc values a profile may touch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KartField {
+    Accel,
+    BrakeForce,
+    NoAccelForce,
+    MaxDriveSpeed,
+    Gravity,
+    DriftFactor,
+    DriftThreshold,
+    HardSpeedCap,
+    AutorunSlotHandicap1,
+    AutorunRankHandicap1,
+    AutorunNotFirstHandicap1,
+    AutorunSlotHandicap2,
+    AutorunRankHandicap2,
+    AutorunNotFirstHandicap2,
+}
+
+impl KartField {
+    pub const ALL: [KartField; 14] = [
+        KartField::Accel,
+        KartField::BrakeForce,
+        KartField::NoAccelForce,
+        KartField::MaxDriveSpeed,
+        KartField::Gravity,
+        KartField::DriftFactor,
+        KartField::DriftThreshold,
+        KartField::HardSpeedCap,
+        KartField::AutorunSlotHandicap1,
+        KartField::AutorunRankHandicap1,
+        KartField::AutorunNotFirstHandicap1,
+        KartField::AutorunSlotHandicap2,
+        KartField::AutorunRankHandicap2,
+        KartField::AutorunNotFirstHandicap2,
+    ];
+
+    pub fn from_name(name: &str) -> Option<KartField> {
+        KartField::ALL.iter().cloned().find(|field| field.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KartField::Accel => "accel",
+            KartField::BrakeForce => "brake_force",
+            KartField::NoAccelForce => "no_accel_force",
+            KartField::MaxDriveSpeed => "max_drive_speed",
+            KartField::Gravity => "gravity",
+            KartField::DriftFactor => "drift_factor",
+            KartField::DriftThreshold => "drift_threshold",
+            KartField::HardSpeedCap => "hard_speed_cap",
+            KartField::AutorunSlotHandicap1 => "autorun_slot_handicap_1",
+            KartField::AutorunRankHandicap1 => "autorun_rank_handicap_1",
+            KartField::AutorunNotFirstHandicap1 => "autorun_not_first_handicap_1",
+            KartField::AutorunSlotHandicap2 => "autorun_slot_handicap_2",
+            KartField::AutorunRankHandicap2 => "autorun_rank_handicap_2",
+            KartField::AutorunNotFirstHandicap2 => "autorun_not_first_handicap_2",
+        }
+    }
+
+    pub fn get_mut(self, kart: &mut KartDlc) -> &mut f32 {
+        match self {
+            KartField::Accel => &mut kart.stats.accel,
+            KartField::BrakeForce => &mut kart.stats.brake_force,
+            KartField::NoAccelForce => &mut kart.stats.no_accel_force,
+            KartField::MaxDriveSpeed => &mut kart.stats.max_drive_speed,
+            KartField::Gravity => &mut kart.stats.gravity,
+            KartField::DriftFactor => &mut kart.stats.drift_factor,
+            KartField::DriftThreshold => &mut kart.stats.drift_threshold,
+            KartField::HardSpeedCap => &mut kart.stats.hard_speed_cap,
+            KartField::AutorunSlotHandicap1 => &mut kart.autorun_slot_handicap_1,
+            KartField::AutorunRankHandicap1 => &mut kart.autorun_rank_handicap_1,
+            KartField::AutorunNotFirstHandicap1 => &mut kart.autorun_not_first_handicap_1,
+            KartField::AutorunSlotHandicap2 => &mut kart.autorun_slot_handicap_2,
+            KartField::AutorunRankHandicap2 => &mut kart.autorun_rank_handicap_2,
+            KartField::AutorunNotFirstHandicap2 => &mut kart.autorun_not_first_handicap_2,
+        }
+    }
+}
+
+pub fn apply_adjustments(adjustments: &[(KartField, Adjustment)], kart: &mut KartDlc) {
+    for &(field, adjustment) in adjustments.iter() {
+        let value = field.get_mut(kart);
+        let old_value = *value;
+        *value = adjustment.apply(old_value);
+        debug!("{}: {} -> {}", field.name(), old_value, *value);
+    }
+}
@@ -0,0 +1,316 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::model::KartDlc;
+use crate::tuning::KartField;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    pub mode: ValidationMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationMode {
+    // Refuse karts with non-finite values or values that would soft-lock a
+    // race; anything else out of range is clamped and reported.
+    Strict,
+    // Clamp everything into range and report what changed.
+    Lenient,
+}
+
+impl Default for ValidationMode {
+    fn default() -> ValidationMode {
+        ValidationMode::Lenient
+    }
+}
+
+// Sane range for a field. Minimums are where a kart stops moving or the
+// handicaps stop making sense. The shipped DLC haven't been surveyed, so the
+// maximums are loose: they only catch values no kart could race with, and
+// are left at f32::MAX where nothing is known to break at the top end.
+// Non-finite values are always out of range.
+//
+// The handicaps scale how hard the AI drives (the DLC seen so far use 1.0),
+// so below 0 has no meaning. Like accel, the top is a loose 10.
+//
+//     accel                         0.001 ..= 10
+//     max_drive_speed               0.001 ..= 100
+//     hard_speed_cap                0.001 ..= 100, and >= max_drive_speed
+//     brake_force, no_accel_force,
+//     gravity, drift_*              0 ..= f32::MAX
+//     autorun_*_handicap_*          0 ..= 10
+//     unknown1, unknown2            any finite value, reset to 0 otherwise
+struct Rule {
+    field: KartField,
+    min: f32,
+    max: f32,
+    // A kart that can't move, or floats off the track, never finishes.
+    soft_lock: bool,
+}
+
+const MAX_ACCEL: f32 = 10.0;
+const MAX_SPEED: f32 = 100.0;
+const MAX_HANDICAP: f32 = 10.0;
+
+const RULES: [Rule; 14] = [
+    Rule { field: KartField::Accel, min: 0.001, max: MAX_ACCEL, soft_lock: true },
+    Rule { field: KartField::BrakeForce, min: 0.0, max: ::std::f32::MAX, soft_lock: false },
+    Rule { field: KartField::NoAccelForce, min: 0.0, max: ::std::f32::MAX, soft_lock: false },
+    Rule { field: KartField::MaxDriveSpeed, min: 0.001, max: MAX_SPEED, soft_lock: true },
+    Rule { field: KartField::Gravity, min: 0.0, max: ::std::f32::MAX, soft_lock: true },
+    Rule { field: KartField::DriftFactor, min: 0.0, max: ::std::f32::MAX, soft_lock: false },
+    Rule { field: KartField::DriftThreshold, min: 0.0, max: ::std::f32::MAX, soft_lock: false },
+    Rule { field: KartField::HardSpeedCap, min: 0.001, max: MAX_SPEED, soft_lock: true },
+    Rule { field: KartField::AutorunSlotHandicap1, min: 0.0, max: MAX_HANDICAP, soft_lock: false },
+    Rule { field: KartField::AutorunRankHandicap1, min: 0.0, max: MAX_HANDICAP, soft_lock: false },
+    Rule { field: KartField::AutorunNotFirstHandicap1, min: 0.0, max: MAX_HANDICAP, soft_lock: false },
+    Rule { field: KartField::AutorunSlotHandicap2, min: 0.0, max: MAX_HANDICAP, soft_lock: false },
+    Rule { field: KartField::AutorunRankHandicap2, min: 0.0, max: MAX_HANDICAP, soft_lock: false },
+    Rule { field: KartField::AutorunNotFirstHandicap2, min: 0.0, max: MAX_HANDICAP, soft_lock: false },
+];
+
+pub struct Issue {
+    pub field: &'static str,
+    pub value: f32,
+    pub reason: String,
+    pub soft_lock: bool,
+}
+
+impl Issue {
+    // NaN or infinite.
+    pub fn non_finite(&self) -> bool {
+        !self.value.is_finite()
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} = {}: {}", self.field, self.value, self.reason)
+    }
+}
+
+// Finds out-of-range values without changing anything.
+pub fn check_kart(kart: &KartDlc) -> Vec<Issue> {
+    let mut kart = *kart;
+    clamp_kart(&mut kart)
+}
+
+// Pulls every value into range, returning what was wrong with the originals.
+// Nothing is known about the unknown stats, so they are only reset when they
+// aren't numbers at all.
+pub fn clamp_kart(kart: &mut KartDlc) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for rule in RULES.iter() {
+        let value = rule.field.get_mut(kart);
+        let (reason, soft_lock) = if !value.is_finite() {
+            (format!("not a finite number, using {}", rule.min.max(0.0)), rule.soft_lock)
+        } else if *value < rule.min {
+            (format!("below the minimum of {}", rule.min), rule.soft_lock)
+        } else if *value > rule.max {
+            // Too fast makes for a bad race, but it still ends.
+            (format!("above the maximum of {}", rule.max), false)
+        } else {
+            continue;
+        };
+        issues.push(Issue {
+            field: rule.field.name(),
+            value: *value,
+            reason: reason,
+            soft_lock: soft_lock,
+        });
+        *value = if value.is_finite() {
+            value.max(rule.min).min(rule.max)
+        } else {
+            rule.min.max(0.0)
+        };
+    }
+    let mut unknowns = [
+        ("unknown1", &mut kart.stats.unknown1),
+        ("unknown2", &mut kart.stats.unknown2),
+    ];
+    for &mut (name, ref mut value) in unknowns.iter_mut() {
+        if !value.is_finite() {
+            issues.push(Issue {
+                field: name,
+                value: **value,
+                reason: "not a finite number, using 0".to_string(),
+                soft_lock: false,
+            });
+            **value = 0.0;
+        }
+    }
+    if kart.stats.hard_speed_cap < kart.stats.max_drive_speed {
+        issues.push(Issue {
+            field: KartField::HardSpeedCap.name(),
+            value: kart.stats.hard_speed_cap,
+            reason: format!("below max_drive_speed ({}), raising it to match", kart.stats.max_drive_speed),
+            soft_lock: false,
+        });
+        kart.stats.hard_speed_cap = kart.stats.max_drive_speed;
+    }
+    issues
+}
+
+// Applies the mode to a kart. Strict mode fails on a non-finite value or a
+// soft-lock and leaves the kart alone; otherwise the kart is clamped and the
+// problems found are returned for reporting.
+pub fn validate_kart(kart: &mut KartDlc, mode: ValidationMode) -> Result<Vec<Issue>> {
+    if mode == ValidationMode::Strict {
+        let issues = check_kart(kart);
+        if let Some(issue) = issues.iter().find(|issue| issue.non_finite()) {
+            return Err(Error::Malformed {
+                path: "kart_dlc".to_string(),
+                reason: issue.to_string(),
+            });
+        }
+        if let Some(issue) = issues.iter().find(|issue| issue.soft_lock) {
+            return Err(Error::Malformed {
+                path: "kart_dlc".to_string(),
+                reason: format!("{}, would soft-lock a race", issue),
+            });
+        }
+    }
+    Ok(clamp_kart(kart))
+}
+
+// The clamped values are exact, so comparing them exactly is fine.
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    use std::f32;
+
+    use crate::model::KartStats;
+
+    fn sample_kart() -> KartDlc {
+        KartDlc {
+            stats: KartStats {
+                accel: 0.05,
+                brake_force: 0.1,
+                no_accel_force: 0.02,
+                max_drive_speed: 2.0,
+                gravity: 0.06,
+                unknown1: 0.5,
+                drift_factor: 0.3,
+                drift_threshold: 0.7,
+                unknown2: -1.0,
+                hard_speed_cap: 4.0,
+            },
+            autorun_slot_handicap_1: 1.0,
+            autorun_rank_handicap_1: 1.0,
+            autorun_not_first_handicap_1: 1.0,
+            autorun_slot_handicap_2: 1.0,
+            autorun_rank_handicap_2: 1.0,
+            autorun_not_first_handicap_2: 1.0,
+            ai_use_dlc_kart: 1,
+            song_name: [0; 64],
+        }
+    }
+
+    fn fields(issues: &[Issue]) -> Vec<&'static str> {
+        issues.iter().map(|issue| issue.field).collect()
+    }
+
+    #[test]
+    fn sane_kart() {
+        for &mode in [ValidationMode::Strict, ValidationMode::Lenient].iter() {
+            let mut kart = sample_kart();
+            assert!(validate_kart(&mut kart, mode).unwrap().is_empty());
+            assert!(kart == sample_kart());
+        }
+    }
+
+    #[test]
+    fn nan_and_inf() {
+        let mut kart = sample_kart();
+        kart.stats.brake_force = f32::NAN;
+        kart.stats.unknown2 = f32::INFINITY;
+        kart.autorun_rank_handicap_2 = f32::NEG_INFINITY;
+
+        let mut strict = kart;
+        let e = validate_kart(&mut strict, ValidationMode::Strict).err().unwrap();
+        assert!(e.to_string().contains("brake_force = NaN: not a finite number"));
+        assert!(strict.stats.brake_force.is_nan());
+
+        let issues = validate_kart(&mut kart, ValidationMode::Lenient).unwrap();
+        assert_eq!(fields(&issues), vec!["brake_force", "autorun_rank_handicap_2", "unknown2"]);
+        assert!(issues.iter().all(Issue::non_finite));
+        assert_eq!(kart.stats.brake_force, 0.0);
+        assert_eq!(kart.stats.unknown2, 0.0);
+        assert_eq!(kart.autorun_rank_handicap_2, 0.0);
+    }
+
+    #[test]
+    fn each_non_finite_unknown_is_rejected() {
+        for &value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY].iter() {
+            let mut kart = sample_kart();
+            kart.stats.unknown1 = value;
+            assert!(validate_kart(&mut kart, ValidationMode::Strict).is_err());
+        }
+    }
+
+    #[test]
+    fn zero_speed() {
+        let mut kart = sample_kart();
+        kart.stats.max_drive_speed = 0.0;
+
+        let mut strict = kart;
+        let e = validate_kart(&mut strict, ValidationMode::Strict).err().unwrap();
+        assert!(e.to_string().contains("max_drive_speed = 0: below the minimum of 0.001, would soft-lock a race"));
+
+        let issues = validate_kart(&mut kart, ValidationMode::Lenient).unwrap();
+        assert_eq!(fields(&issues), vec!["max_drive_speed"]);
+        assert!(issues[0].soft_lock);
+        assert_eq!(kart.stats.max_drive_speed, 0.001);
+    }
+
+    #[test]
+    fn cap_below_speed() {
+        for &mode in [ValidationMode::Strict, ValidationMode::Lenient].iter() {
+            let mut kart = sample_kart();
+            kart.stats.max_drive_speed = 3.0;
+            kart.stats.hard_speed_cap = 2.5;
+            let issues = validate_kart(&mut kart, mode).unwrap();
+            assert_eq!(fields(&issues), vec!["hard_speed_cap"]);
+            assert!(!issues[0].soft_lock);
+            assert_eq!(kart.stats.hard_speed_cap, 3.0);
+        }
+    }
+
+    #[test]
+    fn cap_follows_clamped_speed() {
+        let mut kart = sample_kart();
+        kart.stats.max_drive_speed = 1e9;
+        kart.stats.hard_speed_cap = 50.0;
+        let issues = validate_kart(&mut kart, ValidationMode::Strict).unwrap();
+        assert_eq!(fields(&issues), vec!["max_drive_speed", "hard_speed_cap"]);
+        assert_eq!(kart.stats.max_drive_speed, MAX_SPEED);
+        assert_eq!(kart.stats.hard_speed_cap, MAX_SPEED);
+    }
+
+    #[test]
+    fn out_of_range_handicaps_are_clamped() {
+        for &mode in [ValidationMode::Strict, ValidationMode::Lenient].iter() {
+            let mut kart = sample_kart();
+            kart.autorun_slot_handicap_1 = -2.0;
+            kart.autorun_not_first_handicap_2 = 1e6;
+            let issues = validate_kart(&mut kart, mode).unwrap();
+            assert_eq!(fields(&issues), vec!["autorun_slot_handicap_1", "autorun_not_first_handicap_2"]);
+            assert_eq!(kart.autorun_slot_handicap_1, 0.0);
+            assert_eq!(kart.autorun_not_first_handicap_2, MAX_HANDICAP);
+        }
+    }
+
+    #[test]
+    fn check_leaves_the_kart_alone() {
+        let mut kart = sample_kart();
+        kart.stats.accel = 50.0;
+        assert_eq!(fields(&check_kart(&kart)), vec!["accel"]);
+        assert_eq!(kart.stats.accel, 50.0);
+    }
+}
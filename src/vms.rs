@@ -0,0 +1,379 @@
+use std::io::{Read, Write};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use crate::error::{Error, Result};
+
+// Layout reference: http://mc.pp.se/dc/vms/fileheader.html
+pub const ICON_WIDTH: usize = 32;
+pub const ICON_HEIGHT: usize = 32;
+pub const ICON_SIZE: usize = ICON_WIDTH * ICON_HEIGHT / 2;
+pub const EYECATCH_WIDTH: usize = 72;
+pub const EYECATCH_HEIGHT: usize = 56;
+pub const BLOCK_SIZE: usize = 0x200;
+
+const BASE_HEADER_LEN: usize = 0x80;
+const CRC_OFFSET: usize = 0x46;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EyecatchType {
+    None,
+    TrueColor,
+    Palette256,
+    Palette16,
+}
+
+impl EyecatchType {
+    pub fn from_u16(value: u16) -> Option<EyecatchType> {
+        match value {
+            0 => Some(EyecatchType::None),
+            1 => Some(EyecatchType::TrueColor),
+            2 => Some(EyecatchType::Palette256),
+            3 => Some(EyecatchType::Palette16),
+            _ => None,
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            EyecatchType::None => 0,
+            EyecatchType::TrueColor => 1,
+            EyecatchType::Palette256 => 2,
+            EyecatchType::Palette16 => 3,
+        }
+    }
+
+    // Size of the eyecatch data (palette included) following the icon bitmaps.
+    pub fn data_len(self) -> usize {
+        let pixels = EYECATCH_WIDTH * EYECATCH_HEIGHT;
+        match self {
+            EyecatchType::None => 0,
+            EyecatchType::TrueColor => pixels * 2,
+            EyecatchType::Palette256 => 256 * 2 + pixels,
+            EyecatchType::Palette16 => 16 * 2 + pixels / 2,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct VmsHeader {
+    pub vmu_description: [u8; 16],
+    pub boot_description: [u8; 32],
+    pub app_id: [u8; 16],
+    pub anim_speed: u16,
+    pub eyecatch_type: EyecatchType,
+    pub crc: u16,
+    pub data_len: u32,
+    pub reserved: [u8; 20],
+    // ARGB4444
+    pub palette: [u16; 16],
+    // 4bpp, two pixels per byte, high nybble first
+    pub icons: Vec<[u8; ICON_SIZE]>,
+    pub eyecatch: Vec<u8>,
+}
+
+impl VmsHeader {
+    pub fn read_from<R>(read: R) -> Result<VmsHeader>
+    where
+        R: Read,
+    {
+        VmsHeader::read_fields(read)
+            .map_err(|e| match e {
+                Error::Io(e) => Error::VmsHeader(format!("truncated header ({})", e)),
+                e => e,
+            })
+    }
+
+    fn read_fields<R>(mut read: R) -> Result<VmsHeader>
+    where
+        R: Read,
+    {
+        let mut vmu_description = [0; 16];
+        read.read_exact(&mut vmu_description)?;
+        let mut boot_description = [0; 32];
+        read.read_exact(&mut boot_description)?;
+        let mut app_id = [0; 16];
+        read.read_exact(&mut app_id)?;
+        let icon_count = read.read_u16::<LE>()?;
+        let anim_speed = read.read_u16::<LE>()?;
+        let eyecatch_raw = read.read_u16::<LE>()?;
+        let eyecatch_type = EyecatchType::from_u16(eyecatch_raw)
+            .ok_or_else(|| Error::VmsHeader(format!("bad eyecatch type {}", eyecatch_raw)))?;
+        let crc = read.read_u16::<LE>()?;
+        let data_len = read.read_u32::<LE>()?;
+        let mut reserved = [0; 20];
+        read.read_exact(&mut reserved)?;
+
+        let mut palette = [0; 16];
+        for color in palette.iter_mut() {
+            *color = read.read_u16::<LE>()?;
+        }
+
+        let mut icons = Vec::with_capacity(icon_count as usize);
+        for _ in 0..icon_count {
+            let mut icon = [0; ICON_SIZE];
+            read.read_exact(&mut icon)?;
+            icons.push(icon);
+        }
+
+        let mut eyecatch = vec![0; eyecatch_type.data_len()];
+        read.read_exact(&mut eyecatch)?;
+
+        Ok(VmsHeader {
+            vmu_description: vmu_description,
+            boot_description: boot_description,
+            app_id: app_id,
+            anim_speed: anim_speed,
+            eyecatch_type: eyecatch_type,
+            crc: crc,
+            data_len: data_len,
+            reserved: reserved,
+            palette: palette,
+            icons: icons,
+            eyecatch: eyecatch,
+        })
+    }
+
+    // The CRC is written as stored; use update_crc once the data follows.
+    pub fn write_to<W>(&self, mut write: W) -> Result<()>
+    where
+        W: Write,
+    {
+        if self.icons.len() > u16::max_value() as usize {
+            return Err(Error::VmsHeader(format!("{} icons don't fit the icon count", self.icons.len())));
+        }
+        write.write_all(&self.vmu_description)?;
+        write.write_all(&self.boot_description)?;
+        write.write_all(&self.app_id)?;
+        write.write_u16::<LE>(self.icon_count())?;
+        write.write_u16::<LE>(self.anim_speed)?;
+        write.write_u16::<LE>(self.eyecatch_type.to_u16())?;
+        write.write_u16::<LE>(self.crc)?;
+        write.write_u32::<LE>(self.data_len)?;
+        write.write_all(&self.reserved)?;
+        for color in self.palette.iter() {
+            write.write_u16::<LE>(*color)?;
+        }
+        for icon in self.icons.iter() {
+            write.write_all(icon)?;
+        }
+        if self.eyecatch.len() != self.eyecatch_type.data_len() {
+            return Err(Error::VmsHeader(
+                format!("eyecatch is 0x{:x} bytes, expected 0x{:x}", self.eyecatch.len(), self.eyecatch_type.data_len())));
+        }
+        write.write_all(&self.eyecatch)?;
+        Ok(())
+    }
+
+    // Always the number of frames in icons; written from that too.
+    pub fn icon_count(&self) -> u16 {
+        self.icons.len() as u16
+    }
+
+    // Offset of the file data from the start of the VMS file.
+    pub fn header_len(&self) -> usize {
+        BASE_HEADER_LEN + ICON_SIZE * self.icons.len() + self.eyecatch_type.data_len()
+    }
+
+    pub fn vmu_description_str(&self) -> String {
+        header_str(&self.vmu_description)
+    }
+
+    pub fn boot_description_str(&self) -> String {
+        header_str(&self.boot_description)
+    }
+
+    pub fn app_id_str(&self) -> String {
+        header_str(&self.app_id)
+    }
+
+    pub fn palette_rgba(&self) -> [[u8; 4]; 16] {
+        let mut colors = [[0; 4]; 16];
+        for (color, &argb) in colors.iter_mut().zip(self.palette.iter()) {
+            *color = argb4444_to_rgba(argb);
+        }
+        colors
+    }
+
+    // Decodes an icon frame into 32x32 RGBA8 pixels.
+    pub fn icon_rgba(&self, idx: usize) -> Option<Vec<u8>> {
+        let icon = self.icons.get(idx)?;
+        let palette = self.palette_rgba();
+        let mut pixels = Vec::with_capacity(ICON_WIDTH * ICON_HEIGHT * 4);
+        for byte in icon.iter() {
+            pixels.extend_from_slice(&palette[(byte >> 4) as usize]);
+            pixels.extend_from_slice(&palette[(byte & 0xf) as usize]);
+        }
+        Some(pixels)
+    }
+}
+
+// CRC-16/XMODEM over header and data, with the CRC field itself treated as 0.
+pub fn calc_crc(file: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for (idx, &byte) in file.iter().enumerate() {
+        let byte = if idx == CRC_OFFSET || idx == CRC_OFFSET + 1 { 0 } else { byte };
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+// Trailing block padding is not covered by the CRC.
+fn crc_range(file: &[u8]) -> Result<&[u8]> {
+    let header = VmsHeader::read_from(file)?;
+    let end = header.header_len() + header.data_len as usize;
+    if end > file.len() {
+        return Err(Error::VmsHeader(format!("data ends at 0x{:x} but the file is only 0x{:x} bytes", end, file.len())));
+    }
+    Ok(&file[..end])
+}
+
+pub fn verify_crc(file: &[u8]) -> Result<()> {
+    let computed = calc_crc(crc_range(file)?);
+    let stored = (&file[CRC_OFFSET..]).read_u16::<LE>()?;
+    if stored != computed {
+        return Err(Error::VmsCrc {
+            stored: stored,
+            computed: computed,
+        });
+    }
+    Ok(())
+}
+
+// Recomputes the CRC of an edited VMS file in place and returns it.
+pub fn update_crc(file: &mut [u8]) -> Result<u16> {
+    let crc = calc_crc(crc_range(file)?);
+    (&mut file[CRC_OFFSET..]).write_u16::<LE>(crc)?;
+    Ok(crc)
+}
+
+fn header_str(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or_else(|| data.len());
+    String::from_utf8_lossy(&data[..end]).trim_end().to_string()
+}
+
+fn argb4444_to_rgba(argb: u16) -> [u8; 4] {
+    let expand = |nybble: u16| (nybble & 0xf) as u8 * 0x11;
+    [expand(argb >> 8), expand(argb >> 4), expand(argb), expand(argb >> 12)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_header() -> VmsHeader {
+        let mut vmu_description = [b' '; 16];
+        vmu_description[..4].copy_from_slice(b"KART");
+        let mut app_id = [0; 16];
+        app_id[..4].copy_from_slice(b"SA2B");
+        let mut palette = [0; 16];
+        palette[1] = 0xf00f;
+        palette[2] = 0x8421;
+        VmsHeader {
+            vmu_description: vmu_description,
+            boot_description: [b' '; 32],
+            app_id: app_id,
+            anim_speed: 4,
+            eyecatch_type: EyecatchType::Palette16,
+            crc: 0,
+            data_len: 3,
+            reserved: [0; 20],
+            palette: palette,
+            icons: vec![[0x12; ICON_SIZE], [0x21; ICON_SIZE]],
+            eyecatch: vec![0x5a; EyecatchType::Palette16.data_len()],
+        }
+    }
+
+    fn sample_file() -> Vec<u8> {
+        let mut file = Vec::new();
+        sample_header().write_to(&mut file).unwrap();
+        file.extend_from_slice(&[1, 2, 3]);
+        file.resize(BLOCK_SIZE * 7, 0);
+        file
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let file = sample_file();
+        let header = VmsHeader::read_from(&file[..]).unwrap();
+        assert_eq!(header.vmu_description_str(), "KART");
+        assert_eq!(header.app_id_str(), "SA2B");
+        assert_eq!(header.icon_count(), 2);
+        assert_eq!(header.anim_speed, 4);
+        assert_eq!(header.eyecatch_type, EyecatchType::Palette16);
+        assert_eq!(header.data_len, 3);
+        assert_eq!(header.header_len(), 0x80 + 2 * 0x200 + 0x20 + 0x7e0);
+        assert_eq!(&file[header.header_len()..header.header_len() + 3], &[1, 2, 3]);
+
+        let mut back = Vec::new();
+        header.write_to(&mut back).unwrap();
+        assert_eq!(&back[..], &file[..header.header_len()]);
+    }
+
+    #[test]
+    fn icon_count_follows_icons() {
+        let mut header = sample_header();
+        header.icons.pop();
+        let mut file = Vec::new();
+        header.write_to(&mut file).unwrap();
+        assert_eq!((&file[0x40..]).read_u16::<LE>().unwrap(), 1);
+        assert_eq!(VmsHeader::read_from(&file[..]).unwrap().icons.len(), 1);
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut file = sample_file();
+        (&mut file[0x44..]).write_u16::<LE>(7).unwrap();
+        let e = VmsHeader::read_from(&file[..]).err().unwrap();
+        assert!(e.to_string().contains("bad eyecatch type 7"));
+        let e = VmsHeader::read_from(&sample_file()[..0x100]).err().unwrap();
+        assert!(e.to_string().contains("truncated header"));
+    }
+
+    #[test]
+    fn crc() {
+        // The CRC-16/XMODEM check value.
+        assert_eq!(calc_crc(b"123456789"), 0x31c3);
+
+        let mut file = sample_file();
+        assert!(verify_crc(&file).is_err());
+        let crc = update_crc(&mut file).unwrap();
+        assert_eq!((&file[CRC_OFFSET..]).read_u16::<LE>().unwrap(), crc);
+        verify_crc(&file).unwrap();
+
+        // Padding past the data isn't covered.
+        let last = file.len() - 1;
+        file[last] ^= 0xff;
+        verify_crc(&file).unwrap();
+
+        let data_start = sample_header().header_len();
+        file[data_start] ^= 0xff;
+        match verify_crc(&file) {
+            Err(Error::VmsCrc { stored, computed }) => {
+                assert_eq!(stored, crc);
+                assert_ne!(computed, crc);
+            }
+            _ => panic!("expected a CRC mismatch"),
+        }
+    }
+
+    #[test]
+    fn icon_rgba() {
+        let header = sample_header();
+        let pixels = header.icon_rgba(0).unwrap();
+        assert_eq!(pixels.len(), ICON_WIDTH * ICON_HEIGHT * 4);
+        // 0x12: palette[1] then palette[2].
+        assert_eq!(&pixels[..4], &[0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(&pixels[4..8], &[0x44, 0x22, 0x11, 0x88]);
+        let pixels = header.icon_rgba(1).unwrap();
+        assert_eq!(&pixels[..4], &[0x44, 0x22, 0x11, 0x88]);
+        assert!(header.icon_rgba(2).is_none());
+    }
+}